@@ -0,0 +1,66 @@
+//! Throughput of encoding and decoding upload chunks.
+//!
+//! Compares the old setup (json encoded 500 byte chunks) with binary data
+//! frames of the chunk sizes used by the client.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use protocol::{
+    upload::{ClientCodec, ServerCodec, UploadRequest},
+    Codec, Encoding,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// bytes pushed through the codec on every iteration
+const TOTAL: usize = 8 * 1024 * 1024;
+
+fn transfer(encoding: Encoding, chunk_size: usize, file: &Bytes) {
+    let mut client = ClientCodec::new();
+    let mut server = ServerCodec::new();
+    client.set_encoding(encoding);
+    server.set_encoding(encoding);
+
+    let mut buf = BytesMut::new();
+    let mut received = 0;
+    let mut offset = 0;
+    while offset < file.len() {
+        let end = (offset + chunk_size).min(file.len());
+        client
+            .encode(UploadRequest::Upload(file.slice(offset..end)), &mut buf)
+            .unwrap();
+        offset = end;
+
+        while let Some(msg) = server.decode(&mut buf).unwrap() {
+            if let UploadRequest::Upload(data) = msg {
+                received += data.len();
+            }
+        }
+    }
+    assert_eq!(received, file.len());
+}
+
+fn upload_chunks(c: &mut Criterion) {
+    let file = Bytes::from((0..TOTAL).map(|i| i as u8).collect::<Vec<_>>());
+
+    let mut group = c.benchmark_group("upload_chunks");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+
+    let cases = [
+        (Encoding::Json, 500),
+        (Encoding::Binary, 500),
+        (Encoding::Binary, 64 * 1024),
+        (Encoding::Binary, 1024 * 1024),
+        (Encoding::Binary, 4 * 1024 * 1024),
+    ];
+    for (encoding, chunk_size) in cases {
+        let id = BenchmarkId::new(format!("{:?}", encoding), chunk_size);
+        group.bench_with_input(id, &chunk_size, |b, &chunk_size| {
+            b.iter(|| transfer(encoding, chunk_size, &file))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, upload_chunks);
+criterion_main!(benches);
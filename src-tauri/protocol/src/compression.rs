@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// zstd level used to compress upload chunks, fast enough to keep up with the network
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codec of the data frames of a connection, negotiated after registration.
+///
+/// Every chunk is compressed on its own, so offsets and sizes exchanged by the
+/// protocol are still those of the uncompressed file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn compress(self, data: Bytes) -> Result<Bytes> {
+        let compressed = match self {
+            Compression::None => return Ok(data),
+            Compression::Zstd => zstd::bulk::compress(&data, ZSTD_LEVEL)?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&data),
+        };
+        Ok(compressed.into())
+    }
+
+    pub fn decompress(self, data: Bytes) -> Result<Bytes> {
+        let decompressed = match self {
+            Compression::None => return Ok(data),
+            Compression::Zstd => zstd::stream::decode_all(&*data).context("invalid zstd chunk")?,
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(&data).context("invalid lz4 chunk")?
+            }
+        };
+        Ok(decompressed.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_compression_round_trip() -> Result<()> {
+        let data = Bytes::from("manifest line\n".repeat(1000));
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(data.clone())?;
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10);
+            }
+            assert_eq!(compression.decompress(compressed)?, data);
+        }
+        assert!(Compression::Lz4.decompress(Bytes::from("garbage")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+#[derive(Serialize, Deserialize)]
+pub enum DownloadRequest {
+    /// remote path of the file to download
+    Register(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum DownloadResponse {
+    /// size of the requested file, `None` if the server rejected the request
+    RegisterResult(Option<u64>),
+    Download(Bytes),
+    /// the whole file has been sent
+    Eof,
+}
+
+impl Message for DownloadRequest {}
+
+impl Message for DownloadResponse {
+    fn into_data(self) -> Result<Bytes, Self> {
+        match self {
+            DownloadResponse::Download(data) => Ok(data),
+            other => Err(other),
+        }
+    }
+
+    fn from_data(data: Bytes) -> Option<Self> {
+        Some(DownloadResponse::Download(data))
+    }
+}
+
+crate::impl_codec!(DownloadRequest, DownloadResponse);
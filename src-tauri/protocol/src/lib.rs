@@ -1,24 +1,89 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+pub mod compression;
+pub mod download;
 pub mod http;
 pub mod register_client;
 pub mod upload;
 
+/// How frames are encoded after switching protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// every message is a json document
+    #[default]
+    Json,
+    /// every frame starts with a tag byte. control messages are still json,
+    /// raw data is sent as is
+    Binary,
+}
+
+/// tag of a json encoded control frame under [`Encoding::Binary`]
+pub const FRAME_CONTROL: u8 = 0;
+/// tag of a raw data frame under [`Encoding::Binary`]
+pub const FRAME_DATA: u8 = 1;
+/// tag of a raw data frame under [`Encoding::Binary`], starting with the
+/// big endian u64 offset of the data in the file
+pub const FRAME_DATA_AT: u8 = 2;
+
+/// max length of a frame. large enough for a json encoded data chunk of 8 MiB
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// A message of the protocol.
+///
+/// Messages carrying raw bytes override the default methods, so that their
+/// payload is sent in a data frame instead of a json array of numbers.
+pub trait Message: Sized {
+    fn into_data(self) -> Result<Bytes, Self> {
+        Err(self)
+    }
+
+    fn from_data(data: Bytes) -> Option<Self> {
+        let _ = data;
+        None
+    }
+
+    /// Like [`Message::into_data`], for data sent along with its offset in the file.
+    fn into_data_at(self) -> Result<(u64, Bytes), Self> {
+        Err(self)
+    }
+
+    fn from_data_at(offset: u64, data: Bytes) -> Option<Self> {
+        let _ = (offset, data);
+        None
+    }
+}
+
+pub trait Codec {
+    fn set_encoding(&mut self, encoding: Encoding);
+}
+
 #[macro_export]
 macro_rules! impl_codec {
     ($req_msg:path, $resp_msg:path) => {
         use tokio_util::codec::LengthDelimitedCodec;
 
+        fn len_codec() -> LengthDelimitedCodec {
+            LengthDelimitedCodec::builder()
+                .max_frame_length($crate::MAX_FRAME_LEN)
+                .new_codec()
+        }
+
         pub struct ServerCodec {
             len_codec: LengthDelimitedCodec,
+            encoding: $crate::Encoding,
         }
 
         pub struct ClientCodec {
             len_codec: LengthDelimitedCodec,
+            encoding: $crate::Encoding,
         }
 
         impl ServerCodec {
             pub fn new() -> Self {
                 Self {
-                    len_codec: Default::default(),
+                    len_codec: len_codec(),
+                    encoding: Default::default(),
                 }
             }
         }
@@ -26,20 +91,32 @@ macro_rules! impl_codec {
         impl ClientCodec {
             pub fn new() -> Self {
                 Self {
-                    len_codec: Default::default(),
+                    len_codec: len_codec(),
+                    encoding: Default::default(),
                 }
             }
         }
 
-        crate::impl_codec!(ServerCodec, $resp_msg, $req_msg);
-        crate::impl_codec!(ClientCodec, $req_msg, $resp_msg);
+        $crate::impl_codec!(ServerCodec, $resp_msg, $req_msg);
+        $crate::impl_codec!(ClientCodec, $req_msg, $resp_msg);
     };
 
     ($codec:path, $encode_msg:path, $decode_msg:path) => {
         const _: () = {
-            use bytes::{Bytes, BytesMut};
+            use anyhow::{anyhow, bail};
+            use bytes::{Buf, BufMut, Bytes, BytesMut};
             use tokio_util::codec::{Decoder, Encoder};
 
+            use $crate::{
+                Encoding, Message, FRAME_CONTROL, FRAME_DATA, FRAME_DATA_AT, MAX_FRAME_LEN,
+            };
+
+            impl $crate::Codec for $codec {
+                fn set_encoding(&mut self, encoding: Encoding) {
+                    self.encoding = encoding;
+                }
+            }
+
             impl Decoder for $codec {
                 type Item = $decode_msg;
 
@@ -49,11 +126,32 @@ macro_rules! impl_codec {
                     &mut self,
                     src: &mut BytesMut,
                 ) -> std::result::Result<Option<Self::Item>, Self::Error> {
-                    let bytes = match self.len_codec.decode(src)? {
+                    let mut bytes = match self.len_codec.decode(src)? {
                         Some(b) => b,
                         None => return Ok(None),
                     };
-                    let msg = serde_json::from_slice(&bytes)?;
+                    let msg = match self.encoding {
+                        Encoding::Json => serde_json::from_slice(&bytes)?,
+                        Encoding::Binary => {
+                            if bytes.is_empty() {
+                                bail!("empty frame");
+                            }
+                            match bytes.get_u8() {
+                                FRAME_CONTROL => serde_json::from_slice(&bytes)?,
+                                FRAME_DATA => <$decode_msg as Message>::from_data(bytes.freeze())
+                                    .ok_or_else(|| anyhow!("unexpected data frame"))?,
+                                FRAME_DATA_AT => {
+                                    if bytes.len() < 8 {
+                                        bail!("data frame too short for an offset");
+                                    }
+                                    let offset = bytes.get_u64();
+                                    <$decode_msg as Message>::from_data_at(offset, bytes.freeze())
+                                        .ok_or_else(|| anyhow!("unexpected data frame"))?
+                                }
+                                tag => bail!("unknown frame tag: {}", tag),
+                            }
+                        }
+                    };
                     Ok(Some(msg))
                 }
             }
@@ -66,11 +164,125 @@ macro_rules! impl_codec {
                     item: $encode_msg,
                     dst: &mut BytesMut,
                 ) -> std::result::Result<(), Self::Error> {
-                    let msg = serde_json::to_vec(&item).unwrap();
-                    self.len_codec.encode(Bytes::copy_from_slice(&msg), dst)?;
+                    let (tag, offset, msg) = match self.encoding {
+                        Encoding::Json => {
+                            let msg = serde_json::to_vec(&item).unwrap();
+                            self.len_codec.encode(Bytes::from(msg), dst)?;
+                            return Ok(());
+                        }
+                        Encoding::Binary => match Message::into_data(item) {
+                            Ok(data) => (FRAME_DATA, None, data),
+                            Err(item) => match Message::into_data_at(item) {
+                                Ok((offset, data)) => (FRAME_DATA_AT, Some(offset), data),
+                                Err(item) => (
+                                    FRAME_CONTROL,
+                                    None,
+                                    Bytes::from(serde_json::to_vec(&item).unwrap()),
+                                ),
+                            },
+                        },
+                    };
+
+                    // same layout as `LengthDelimitedCodec`, written by hand so the
+                    // payload is copied only once, straight into the write buffer
+                    let header_len = if offset.is_some() { 9 } else { 1 };
+                    let len = header_len + msg.len();
+                    if len > MAX_FRAME_LEN {
+                        bail!("frame too large: {} bytes", len);
+                    }
+                    dst.reserve(4 + len);
+                    dst.put_u32(len as u32);
+                    dst.put_u8(tag);
+                    if let Some(offset) = offset {
+                        dst.put_u64(offset);
+                    }
+                    dst.extend_from_slice(&msg);
                     Ok(())
                 }
             }
         };
     };
 }
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{
+        upload::{ClientCodec, ConflictPolicy, FileIdentity, ServerCodec, UploadRequest},
+        Codec, Encoding,
+    };
+
+    fn round_trip(encoding: Encoding, msg: UploadRequest) -> Result<(usize, UploadRequest)> {
+        let mut client = ClientCodec::new();
+        let mut server = ServerCodec::new();
+        client.set_encoding(encoding);
+        server.set_encoding(encoding);
+
+        let mut buf = BytesMut::new();
+        client.encode(msg, &mut buf)?;
+        let len = buf.len();
+        let msg = server.decode(&mut buf)?.expect("a whole frame");
+        assert!(buf.is_empty());
+        Ok((len, msg))
+    }
+
+    #[test]
+    fn t_binary_data_frame() -> Result<()> {
+        let data = Bytes::from(vec![0xffu8; 1024]);
+        let (json_len, _) = round_trip(Encoding::Json, UploadRequest::Upload(data.clone()))?;
+        let (bin_len, msg) = round_trip(Encoding::Binary, UploadRequest::Upload(data.clone()))?;
+
+        assert!(matches!(msg, UploadRequest::Upload(d) if d == data));
+        // 4 bytes length + 1 byte tag
+        assert_eq!(bin_len, data.len() + 5);
+        assert!(json_len > data.len() * 3);
+        Ok(())
+    }
+
+    #[test]
+    fn t_binary_data_at_frame() -> Result<()> {
+        let data = Bytes::from(vec![0xffu8; 1024]);
+        let msg = UploadRequest::UploadAt {
+            offset: 1 << 40,
+            data: data.clone(),
+        };
+        let (bin_len, msg) = round_trip(Encoding::Binary, msg)?;
+
+        assert!(matches!(
+            msg,
+            UploadRequest::UploadAt { offset, data: d } if offset == 1 << 40 && d == data
+        ));
+        // 4 bytes length + 1 byte tag + 8 bytes offset
+        assert_eq!(bin_len, data.len() + 13);
+        Ok(())
+    }
+
+    #[test]
+    fn t_binary_control_frame() -> Result<()> {
+        let register = UploadRequest::Register {
+            path: "/a/b.mp4".to_string(),
+            identity: FileIdentity {
+                size: 42,
+                modified: 1,
+            },
+            on_conflict: ConflictPolicy::Rename,
+        };
+        let (_, msg) = round_trip(Encoding::Binary, register)?;
+        match msg {
+            UploadRequest::Register {
+                path,
+                identity,
+                on_conflict,
+            } => {
+                assert_eq!(path, "/a/b.mp4");
+                assert_eq!(identity.size, 42);
+                assert_eq!(on_conflict, ConflictPolicy::Rename);
+            }
+            _ => panic!("expect register msg"),
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,30 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{Encoding, Message};
+
 #[derive(Serialize, Deserialize)]
 pub enum RegisterClientReq {
-    SwitchProtocol(ClientType),
+    /// switch to the protocol of the client type, using the preferred encoding if possible.
+    /// carries the session token got by logging in over http
+    SwitchProtocol(ClientType, Encoding, Option<String>),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum ClientType {
     Upload,
+    Download,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum RegisterResult {
-    Ok,
+    /// encoding chosen by the server for the rest of the connection
+    Ok(Encoding),
+    /// the token is missing or expired
+    Unauthorized,
     Err(Option<String>),
 }
 
+impl Message for RegisterClientReq {}
+impl Message for RegisterResult {}
+
 crate::impl_codec!(RegisterClientReq, RegisterResult);
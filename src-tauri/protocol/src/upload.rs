@@ -1,14 +1,122 @@
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
+use crate::{compression::Compression, Message};
+
 #[derive(Serialize, Deserialize)]
 pub enum UploadRequest {
-    Register(String),
-    Upload(Vec<u8>),
+    Register {
+        /// remote path of the uploaded file
+        path: String,
+        identity: FileIdentity,
+        /// what to do if a file already exists at `path`
+        #[serde(default)]
+        on_conflict: ConflictPolicy,
+    },
+    Upload(Bytes),
+    /// registers one more connection for the file registered by `Register`, which
+    /// sends a part of the file with `UploadAt`. answered by `RegisterResult`
+    RegisterPart {
+        path: String,
+        identity: FileIdentity,
+    },
+    /// offers codecs for the data sent on this connection, most preferred first.
+    /// sent after a successful registration, answered by `Negotiated`
+    Negotiate {
+        compression: Vec<Compression>,
+    },
+    /// data of the file at `offset`, parts of a file may be sent over several
+    /// connections at the same time
+    UploadAt {
+        offset: u64,
+        data: Bytes,
+    },
+    /// sent after the last `UploadAt` of a connection, answered by `PartReceived`
+    EndPart,
+    /// hex encoded SHA-256 of the whole file, sent after the last chunk
+    Verify(String),
+    /// asks the server to commit the uploaded file to its destination
+    Finish,
+    /// the upload is stopped by the user. the server keeps the partial file
+    /// for a later resume if `keep_partial`, otherwise deletes it
+    Cancel {
+        keep_partial: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum UploadResponse {
-    RegisterResult(bool),
+    /// number of bytes the server already holds for this file,
+    /// `None` if the server rejected the request
+    RegisterResult(Option<u64>),
+    /// a file exists at the registered path, the upload goes to `path` instead.
+    /// answers `Register` with [`ConflictPolicy::Rename`]
+    Renamed { path: String, offset: u64 },
+    /// a file exists at the registered path and is kept, there is nothing to upload.
+    /// answers `Register` with [`ConflictPolicy::Skip`]
+    Skipped,
+    /// a file exists at the registered path, the connection is closed.
+    /// answers `Register` with [`ConflictPolicy::Fail`]
+    Conflict,
+    /// the codec of the data frames sent on this connection from now on,
+    /// [`Compression::None`] if the server supports none of those offered
+    Negotiated { compression: Compression },
+    /// whether the received file matches the hash sent by `UploadRequest::Verify`
+    VerifyResult(bool),
+    /// the file has been written to `path` on the server
+    Committed { size: u64, path: String },
+    /// bytes received by `UploadAt` on this connection, all written to the file
+    PartReceived(u64),
+}
+
+/// What the server does when the destination of an upload already exists.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// replace the existing file once the upload is committed
+    Overwrite,
+    /// keep the existing file and upload nothing
+    Skip,
+    /// upload to a free path, made by adding a suffix to the file name
+    Rename,
+    /// reject the upload
+    #[default]
+    Fail,
 }
 
+/// Identifies the content of a local file, so that the server can tell whether
+/// a partially uploaded file belongs to the same source and can be resumed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileIdentity {
+    pub size: u64,
+    /// last modified time of the local file, in seconds since unix epoch
+    pub modified: u64,
+}
+
+impl Message for UploadRequest {
+    fn into_data(self) -> Result<Bytes, Self> {
+        match self {
+            UploadRequest::Upload(data) => Ok(data),
+            other => Err(other),
+        }
+    }
+
+    fn from_data(data: Bytes) -> Option<Self> {
+        Some(UploadRequest::Upload(data))
+    }
+
+    fn into_data_at(self) -> Result<(u64, Bytes), Self> {
+        match self {
+            UploadRequest::UploadAt { offset, data } => Ok((offset, data)),
+            other => Err(other),
+        }
+    }
+
+    fn from_data_at(offset: u64, data: Bytes) -> Option<Self> {
+        Some(UploadRequest::UploadAt { offset, data })
+    }
+}
+
+impl Message for UploadResponse {}
+
 crate::impl_codec!(UploadRequest, UploadResponse);
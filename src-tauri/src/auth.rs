@@ -0,0 +1,343 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Mutex, OnceLock, RwLock,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use protocol::http::Response;
+use reqwest::{header::CONTENT_TYPE, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    client::http_client,
+    credentials::{self, CredentialStore},
+    my_err::{MyResult, Unauthorized},
+    profile,
+    settings::RemoteServerConfig,
+};
+
+/// keys of the session in the credential store
+const TOKEN_KEY: &str = "token";
+const LOGIN_KEY: &str = "login";
+
+/// Keys of the session of the active profile in the credential store.
+#[derive(Clone, PartialEq, Eq)]
+struct SessionKeys {
+    token: String,
+    login: String,
+}
+
+impl SessionKeys {
+    fn of_active_profile() -> Self {
+        let profile = profile::active();
+        let key = |name: &str| match profile.credentials() {
+            // the default profile keeps the session saved before there were profiles
+            profile::DEFAULT_PROFILE => name.to_string(),
+            credentials => format!("{}/{}", credentials, name),
+        };
+        Self {
+            token: key(TOKEN_KEY),
+            login: key(LOGIN_KEY),
+        }
+    }
+}
+
+/// The session with the server, shared by the http and tcp channels.
+static SESSION: Session = Session {
+    token: RwLock::new(None),
+    credentials: Mutex::new(None),
+    relogin: tokio::sync::Mutex::const_new(()),
+    restored: AtomicBool::new(false),
+    restore_done: Notify::const_new(),
+};
+
+struct Session {
+    /// sent as a bearer token over http, and in the register handshake over tcp
+    token: RwLock<Option<String>>,
+    /// kept to log in again when the token expires
+    credentials: Mutex<Option<Credentials>>,
+    /// requests rejected at the same time log in again only once
+    relogin: tokio::sync::Mutex<()>,
+    /// whether the session saved by the previous run has been looked for
+    restored: AtomicBool,
+    /// wakes the requests waiting for the session of the previous run
+    restore_done: Notify,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct LoginData {
+    token: String,
+}
+
+#[instrument(skip(password))]
+#[tauri::command]
+pub async fn login(username: String, password: String) -> MyResult<()> {
+    do_login(Credentials { username, password }).await?;
+    info!("logged in");
+    Ok(())
+}
+
+#[tauri::command]
+pub fn logout() {
+    *SESSION.token.write().unwrap() = None;
+    *SESSION.credentials.lock().unwrap() = None;
+    save_session();
+    info!("logged out");
+}
+
+/// Restores the session of the active profile saved by a previous run of the app.
+///
+/// Requests wait for the first restore to end, whether there is a session to
+/// restore or not, so they are not sent without it.
+pub async fn restore_session() {
+    restore_saved().await;
+    SESSION.restored.store(true, Ordering::Release);
+    SESSION.restore_done.notify_waiters();
+}
+
+/// Waits until the session of the previous run is restored, see [`restore_session`].
+pub async fn wait_restored() {
+    // registered before the check, so a restore ending in between is not missed
+    let done = SESSION.restore_done.notified();
+    if !SESSION.restored.load(Ordering::Acquire) {
+        done.await;
+    }
+}
+
+async fn restore_saved() {
+    let keys = SessionKeys::of_active_profile();
+    let (done, restored) = oneshot::channel();
+    run_store_job(StoreJob::Restore {
+        keys: keys.clone(),
+        done,
+    });
+    let (credentials, restored_token) = match restored.await {
+        Ok(Ok(session)) => session,
+        Ok(Err(err)) => {
+            warn!(?err, "failed to restore session");
+            return;
+        }
+        // there is no credential store
+        Err(_) => return,
+    };
+
+    let logged_in = token().is_some() || SESSION.credentials.lock().unwrap().is_some();
+    if logged_in || SessionKeys::of_active_profile() != keys {
+        debug!("logged in or switched profile while the session was restored");
+        return;
+    }
+    *SESSION.credentials.lock().unwrap() = credentials;
+    *SESSION.token.write().unwrap() = restored_token;
+    info!(logged_in = token().is_some(), "session restored");
+}
+
+/// Drops the session of the previous profile, and restores the one of the
+/// active profile.
+pub async fn switch_session() {
+    *SESSION.token.write().unwrap() = None;
+    *SESSION.credentials.lock().unwrap() = None;
+    restore_session().await;
+}
+
+/// Saves the session to the credential store in the background.
+fn save_session() {
+    // queued under the lock, so the snapshots reach the worker in the order taken
+    let credentials = SESSION.credentials.lock().unwrap();
+    run_store_job(StoreJob::Save(SavedSession {
+        // taken now, the profile may be switched before the session is saved
+        keys: SessionKeys::of_active_profile(),
+        token: token(),
+        credentials: credentials.clone(),
+    }));
+}
+
+/// Work on the credential store, done in order by a single thread, as the
+/// keyring and the key derivation of the encrypted file are slow.
+enum StoreJob {
+    Save(SavedSession),
+    Restore {
+        keys: SessionKeys,
+        done: oneshot::Sender<Result<RestoredSession>>,
+    },
+}
+
+struct SavedSession {
+    keys: SessionKeys,
+    token: Option<String>,
+    credentials: Option<Credentials>,
+}
+
+/// credentials and token read from the store
+type RestoredSession = (Option<Credentials>, Option<String>);
+
+impl StoreJob {
+    fn run(self, store: &CredentialStore) {
+        match self {
+            StoreJob::Save(session) => {
+                if let Err(err) = session.write(store) {
+                    warn!(?err, "failed to save session");
+                }
+            }
+            StoreJob::Restore { keys, done } => {
+                // the receiver is gone if the restore is no longer awaited
+                let _ = done.send(read_session(store, &keys));
+            }
+        }
+    }
+
+    /// Whether a later save of the same session replaces this one, before
+    /// anything reads it.
+    fn superseded(&self, later: &[StoreJob]) -> bool {
+        let StoreJob::Save(save) = self else {
+            return false;
+        };
+        for job in later {
+            match job {
+                StoreJob::Save(next) if next.keys == save.keys => return true,
+                StoreJob::Save(_) => {}
+                StoreJob::Restore { .. } => return false,
+            }
+        }
+        false
+    }
+}
+
+impl SavedSession {
+    fn write(self, store: &CredentialStore) -> Result<()> {
+        match self.token {
+            Some(token) => store.set(&self.keys.token, &token)?,
+            None => store.delete(&self.keys.token)?,
+        }
+        match self.credentials {
+            Some(credentials) => {
+                store.set(&self.keys.login, &serde_json::to_string(&credentials)?)?
+            }
+            None => store.delete(&self.keys.login)?,
+        }
+        Ok(())
+    }
+}
+
+fn read_session(store: &CredentialStore, keys: &SessionKeys) -> Result<RestoredSession> {
+    let credentials = match store.get(&keys.login)? {
+        Some(login) => Some(serde_json::from_str(&login).context("invalid saved login")?),
+        None => None,
+    };
+    Ok((credentials, store.get(&keys.token)?))
+}
+
+/// Queues a job for the thread working on the credential store, started on first use.
+fn run_store_job(job: StoreJob) {
+    static JOBS: OnceLock<mpsc::Sender<StoreJob>> = OnceLock::new();
+    let jobs = JOBS.get_or_init(|| {
+        let (jobs, queued) = mpsc::channel();
+        std::thread::spawn(move || store_worker(queued));
+        jobs
+    });
+    if jobs.send(job).is_err() {
+        warn!("credential store worker stopped");
+    }
+}
+
+fn store_worker(queued: mpsc::Receiver<StoreJob>) {
+    while let Ok(job) = queued.recv() {
+        let mut batch = vec![job];
+        batch.extend(queued.try_iter());
+        let superseded: Vec<_> = (0..batch.len())
+            .map(|i| batch[i].superseded(&batch[i + 1..]))
+            .collect();
+        for (job, superseded) in batch.into_iter().zip(superseded) {
+            // jobs queued before the store is open are dropped
+            match credentials::store() {
+                Some(store) if !superseded => job.run(store),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The token of the current session, if logged in.
+pub fn token() -> Option<String> {
+    SESSION.token.read().unwrap().clone()
+}
+
+async fn do_login(credentials: Credentials) -> Result<()> {
+    // sent without the request macros, which would log in again on 401
+    let resp = http_client()?
+        .post(RemoteServerConfig::url_login())
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&credentials)?)
+        .send()
+        .await
+        .context("send login request")?;
+    if resp.status() == StatusCode::UNAUTHORIZED {
+        bail!(Unauthorized);
+    }
+    ensure!(
+        resp.status().is_success(),
+        "login: code = {}",
+        resp.status()
+    );
+    let resp: Response<LoginData> =
+        serde_json::from_str(&resp.text().await?).context("invalid login response")?;
+    let data = resp.to_result()?.context("server sent no token")?;
+
+    *SESSION.token.write().unwrap() = Some(data.token);
+    *SESSION.credentials.lock().unwrap() = Some(credentials);
+    save_session();
+    Ok(())
+}
+
+/// Logs in again with the credentials of the last login, after the server
+/// rejected `stale`.
+///
+/// Returns whether there is a new token to retry with.
+pub async fn relogin(stale: Option<&str>) -> Result<bool> {
+    let _relogin = SESSION.relogin.lock().await;
+    if token().as_deref() != stale {
+        debug!("token renewed by another request");
+        return Ok(true);
+    }
+    let Some(credentials) = SESSION.credentials.lock().unwrap().clone() else {
+        return Ok(false);
+    };
+    info!("session expired, logging in again");
+    do_login(credentials).await?;
+    Ok(true)
+}
+
+/// Sends `req` with the session token, logging in again once if the server
+/// answers 401.
+pub async fn send(req: RequestBuilder) -> Result<reqwest::Response> {
+    wait_restored().await;
+    // bodies of the requests are strings, so they can always be sent again
+    let retry = req.try_clone();
+    let stale = token();
+    let mut resp = with_token(req, stale.as_deref()).send().await?;
+    if resp.status() == StatusCode::UNAUTHORIZED {
+        if let Some(retry) = retry {
+            if relogin(stale.as_deref()).await? {
+                resp = with_token(retry, token().as_deref()).send().await?;
+            }
+        }
+    }
+    if resp.status() == StatusCode::UNAUTHORIZED {
+        bail!(Unauthorized);
+    }
+    Ok(resp)
+}
+
+fn with_token(req: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+    match token {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
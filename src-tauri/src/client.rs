@@ -1,39 +1,434 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context as TaskContext, Poll},
+    time::SystemTime,
+};
+
 use anyhow::{bail, Context, Result};
 use futures::{SinkExt, StreamExt};
-use protocol::register_client::{ClientCodec, ClientType, RegisterClientReq};
-use tokio::net::TcpStream;
+use protocol::{
+    register_client::{ClientCodec, ClientType, RegisterClientReq, RegisterResult},
+    Codec, Encoding,
+};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
 use tokio_util::codec::{Decoder, Framed};
+use tracing::debug;
+
+use crate::{
+    auth,
+    my_err::Unauthorized,
+    profile,
+    settings::{HttpsConfig, RemoteServerConfig, TlsConfig},
+};
 
-use crate::settings::get_settings;
+/// encoding asked for when switching protocol, the server may fall back to json
+const PREFERRED_ENCODING: Encoding = Encoding::Binary;
 
-pub async fn build_client_frame<C>(
-    codec: C,
+pub async fn build_client_frame<C: Codec>(
+    mut codec: C,
     client_type: ClientType,
-) -> Result<Framed<TcpStream, C>> {
-    let server = &get_settings().remote_server.tcp;
+) -> Result<Framed<ClientStream, C>> {
+    auth::wait_restored().await;
+    let mut relogged = false;
+    loop {
+        let token = auth::token();
+        let stream = connect(profile::active().server()).await?;
+        let mut framed = ClientCodec::new().framed(stream);
+
+        framed
+            .send(RegisterClientReq::SwitchProtocol(
+                client_type,
+                PREFERRED_ENCODING,
+                token.clone(),
+            ))
+            .await
+            .context("send switch msg")?;
+
+        match framed.next().await {
+            Some(Ok(msg)) => match msg {
+                RegisterResult::Ok(encoding) => {
+                    debug!(?encoding, "protocol switched");
+                    codec.set_encoding(encoding);
+                    return Ok(Framed::new(framed.into_inner(), codec));
+                }
+                RegisterResult::Unauthorized if !relogged => {
+                    relogged = true;
+                    if !auth::relogin(token.as_deref()).await? {
+                        bail!(Unauthorized);
+                    }
+                }
+                RegisterResult::Unauthorized => bail!(Unauthorized),
+                RegisterResult::Err(reason) => {
+                    bail!("server rejected register request. reason = {:?}", reason)
+                }
+            },
+            Some(Err(err)) => {
+                bail!("failed to decode server msg: {err}");
+            }
+            None => {
+                bail!("server closed connection early");
+            }
+        }
+    }
+}
 
-    let stream = TcpStream::connect(server).await?;
-    let mut framed = ClientCodec::new().framed(stream);
+/// A connection to the tcp server, encrypted if `tls` is set in the active profile.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
 
-    framed
-        .send(RegisterClientReq::SwitchProtocol(client_type))
+async fn connect(config: &RemoteServerConfig) -> Result<ClientStream> {
+    let stream = TcpStream::connect(&config.tcp)
         .await
-        .context("send switch msg")?;
+        .with_context(|| format!("connect {}", config.tcp))?;
+    let Some(tls) = &config.tls else {
+        return Ok(ClientStream::Plain(stream));
+    };
 
-    match framed.next().await {
-        Some(Ok(msg)) => match msg {
-            protocol::register_client::RegisterResult::Ok => {
-                Ok(Framed::new(framed.into_inner(), codec))
-            }
-            protocol::register_client::RegisterResult::Err(reason) => {
-                bail!("server rejected register request. reason = {:?}", reason)
+    let server_name = match &tls.server_name {
+        Some(name) => name.as_str(),
+        None => host_of(&config.tcp),
+    };
+    let server_name = ServerName::try_from(server_name)
+        .with_context(|| format!("invalid tls server name: {}", server_name))?;
+    let connector = TlsConnector::from(Arc::new(tls_config(tls)?));
+    let stream = connector
+        .connect(server_name, stream)
+        .await
+        .context("tls handshake")?;
+    debug!("tls connected");
+    Ok(ClientStream::Tls(Box::new(stream)))
+}
+
+fn tls_config(tls: &TlsConfig) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store(tls.ca_file.as_deref())?);
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .context("invalid client certificate")?,
+        (None, None) => builder.with_no_client_auth(),
+        // rejected by the validation of the settings, never sent without client auth
+        _ => bail!("tls client_cert and client_key must be set together"),
+    };
+    Ok(config)
+}
+
+/// client of the http api of the active profile, built on first use
+static HTTP_CLIENT: RwLock<Option<reqwest::Client>> = RwLock::new(None);
+
+/// The client of the http api, shared by all requests.
+pub fn http_client() -> Result<reqwest::Client> {
+    if let Some(client) = HTTP_CLIENT.read().unwrap().as_ref() {
+        return Ok(client.clone());
+    }
+    let mut cached = HTTP_CLIENT.write().unwrap();
+    if let Some(client) = cached.as_ref() {
+        return Ok(client.clone());
+    }
+    let client = build_http_client(&profile::active().server().https)?;
+    Ok(cached.insert(client).clone())
+}
+
+/// Drops the http client, so the next request builds one for the active profile.
+pub fn reset_http_client() {
+    *HTTP_CLIENT.write().unwrap() = None;
+}
+
+fn build_http_client(https: &HttpsConfig) -> Result<reqwest::Client> {
+    let roots = root_store(https.ca_file.as_deref())?;
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let tls = if https.pinned_certs.is_empty() {
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        let verifier = PinnedCertVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins: https
+                .pinned_certs
+                .iter()
+                .map(|pin| normalize_pin(pin))
+                .collect(),
+        };
+        builder
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth()
+    };
+    let client = reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .build()
+        .context("build http client")?;
+    Ok(client)
+}
+
+/// Trusts the server only if its certificate is one of the pinned ones, on top
+/// of the usual verification against the trusted CAs.
+struct PinnedCertVerifier {
+    inner: WebPkiVerifier,
+    /// lower case hex SHA-256 of the DER certificates
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let fingerprint = format!("{:x}", Sha256::digest(&end_entity.0));
+        if !self.pins.contains(&fingerprint) {
+            return Err(rustls::Error::General(format!(
+                "server certificate {} is not pinned",
+                fingerprint
+            )));
+        }
+        Ok(verified)
+    }
+}
+
+/// Pins are accepted in any case and with colons, as printed by `openssl x509 -fingerprint`.
+fn normalize_pin(pin: &str) -> String {
+    pin.replace(':', "").to_ascii_lowercase()
+}
+
+/// The CAs of `ca_file`, the webpki roots if not set.
+fn root_store(ca_file: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            for cert in read_certs(ca_file)? {
+                roots.add(&cert)?;
             }
-        },
-        Some(Err(err)) => {
-            bail!("failed to decode server msg: {err}");
         }
-        None => {
-            bail!("server closed connection early");
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    Ok(roots)
+}
+
+/// The host part of a `host:port` address.
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("open {}", path.display()))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("invalid PEM file {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificate in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("open {}", path.display()))?);
+    for item in rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("invalid PEM file {}", path.display()))?
+    {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key in {}", path.display())
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use rustls::ServerConfig;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+
+    /// A local server with a self-signed certificate for `localhost`, which
+    /// answers every connection with `response` once it received 4 bytes.
+    ///
+    /// Returns the address of the server, the PEM file of its certificate and
+    /// the SHA-256 of the certificate.
+    async fn stand_in_server(
+        name: &str,
+        response: &'static [u8],
+    ) -> Result<(String, PathBuf, String)> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let ca_file =
+            std::env::temp_dir().join(format!("zcode-bench-{}-{}.pem", name, std::process::id()));
+        std::fs::write(&ca_file, cert.serialize_pem()?)?;
+        let der = cert.serialize_der()?;
+        let fingerprint = format!("{:x}", Sha256::digest(&der));
+
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(der)],
+                PrivateKey(cert.serialize_private_key_der()),
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut buf = [0; 4];
+                if stream.read_exact(&mut buf).await.is_ok() {
+                    let _ = stream.write_all(response).await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+        Ok((addr.to_string(), ca_file, fingerprint))
+    }
+
+    #[tokio::test]
+    async fn t_tls_connect() -> Result<()> {
+        let (addr, ca_file, _) = stand_in_server("tcp", b"pong").await?;
+
+        let mut config = RemoteServerConfig {
+            http: String::new(),
+            tcp: addr,
+            tls: Some(TlsConfig {
+                ca_file: Some(ca_file.clone()),
+                server_name: Some("localhost".to_string()),
+                client_cert: None,
+                client_key: None,
+            }),
+            https: HttpsConfig::default(),
+        };
+        let mut stream = connect(&config).await?;
+        assert!(matches!(stream, ClientStream::Tls(_)));
+        stream.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+
+        // a client certificate without its key is an error, not a connection without client auth
+        let tls = config.tls.as_mut().unwrap();
+        tls.client_cert = Some(ca_file.clone());
+        assert!(tls_config(tls).is_err());
+        config.http = "localhost:36743".to_string();
+        assert!(config.validate().is_err());
+        config.tls.as_mut().unwrap().client_cert = None;
+        assert!(config.validate().is_ok());
+
+        // the self-signed certificate is not trusted by default
+        config.tls.as_mut().unwrap().ca_file = None;
+        assert!(connect(&config).await.is_err());
+
+        std::fs::remove_file(ca_file)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_https_pinned_cert() -> Result<()> {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+        let (addr, ca_file, fingerprint) = stand_in_server("https", response).await?;
+        let port = addr.rsplit_once(':').unwrap().1;
+        let url = format!("https://localhost:{}/", port);
+
+        let mut https = HttpsConfig {
+            ca_file: Some(ca_file.clone()),
+            pinned_certs: vec![fingerprint.to_uppercase()],
+        };
+        let client = build_http_client(&https)?;
+        assert_eq!(client.get(&url).send().await?.text().await?, "ok");
+
+        https.pinned_certs = vec!["00".repeat(32)];
+        let client = build_http_client(&https)?;
+        assert!(client.get(&url).send().await.is_err());
+
+        std::fs::remove_file(ca_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn t_host_of() {
+        assert_eq!(host_of("10.0.10.3:48371"), "10.0.10.3");
+        assert_eq!(host_of("[::1]:48371"), "::1");
+        assert_eq!(host_of("localhost"), "localhost");
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::utils::write_atomic_private;
+
+/// name of the app in the keyring
+const SERVICE: &str = "zcode-bench";
+/// env var holding the passphrase of the credentials file
+pub const PASSPHRASE_ENV: &str = "ZCODE_BENCH_PASSPHRASE";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+static STORE: OnceLock<CredentialStore> = OnceLock::new();
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    /// the keyring if there is one, otherwise the encrypted file
+    #[default]
+    Auto,
+    Keyring,
+    File,
+}
+
+/// Where secrets are kept: the OS keyring, or a file encrypted with a
+/// passphrase where there is no keyring, like on headless machines.
+pub enum CredentialStore {
+    Keyring,
+    File(EncryptedFile),
+}
+
+impl CredentialStore {
+    pub fn open(kind: StoreKind, file: PathBuf) -> Result<Self> {
+        let use_keyring = match kind {
+            StoreKind::Auto => keyring_available(),
+            StoreKind::Keyring => true,
+            StoreKind::File => false,
+        };
+        if use_keyring {
+            return Ok(Self::Keyring);
+        }
+        let passphrase = std::env::var(PASSPHRASE_ENV).with_context(|| {
+            format!(
+                "no keyring to store credentials, and {} is not set to encrypt them in a file",
+                PASSPHRASE_ENV
+            )
+        })?;
+        Ok(Self::File(EncryptedFile::new(file, passphrase)))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self {
+            Self::Keyring => match keyring::Entry::new(SERVICE, key)?.get_password() {
+                Ok(secret) => Ok(Some(secret)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Self::File(file) => Ok(file.load()?.remove(key)),
+        }
+    }
+
+    pub fn set(&self, key: &str, secret: &str) -> Result<()> {
+        match self {
+            Self::Keyring => keyring::Entry::new(SERVICE, key)?.set_password(secret)?,
+            Self::File(file) => file.update(|secrets| {
+                secrets.insert(key.to_string(), secret.to_string());
+            })?,
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Self::Keyring => match keyring::Entry::new(SERVICE, key)?.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => {}
+                Err(err) => return Err(err.into()),
+            },
+            Self::File(file) => file.update(|secrets| {
+                secrets.remove(key);
+            })?,
+        }
+        Ok(())
+    }
+}
+
+/// Opens the store of the app, where the session is kept between runs.
+pub fn init(kind: StoreKind, file: PathBuf) -> Result<()> {
+    let store = CredentialStore::open(kind, file)?;
+    match &store {
+        CredentialStore::Keyring => info!("credentials stored in the keyring"),
+        CredentialStore::File(file) => info!(path = ?file.path, "credentials stored in a file"),
+    }
+    if STORE.set(store).is_err() {
+        bail!("credential store opened twice");
+    }
+    Ok(())
+}
+
+/// The store of the app, none if it could not be opened.
+pub fn store() -> Option<&'static CredentialStore> {
+    STORE.get()
+}
+
+fn keyring_available() -> bool {
+    let probe = keyring::Entry::new(SERVICE, "probe").and_then(|entry| entry.get_password());
+    match probe {
+        Ok(_) | Err(keyring::Error::NoEntry) => true,
+        Err(err) => {
+            warn!(?err, "no keyring available");
+            false
+        }
+    }
+}
+
+/// Secrets saved as json, encrypted with AES-256-GCM under a key derived from
+/// the passphrase by Argon2.
+///
+/// The file starts with the salt of the key and the nonce.
+pub struct EncryptedFile {
+    path: PathBuf,
+    passphrase: String,
+    /// the file is read and written as a whole
+    lock: Mutex<()>,
+}
+
+impl EncryptedFile {
+    fn new(path: PathBuf, passphrase: String) -> Self {
+        Self {
+            path,
+            passphrase,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn load(&self) -> Result<HashMap<String, String>> {
+        let _lock = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, String>)) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let mut secrets = self.read()?;
+        f(&mut secrets);
+        self.write(&secrets)
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err).context("read credentials file"),
+        };
+        ensure!(
+            data.len() > SALT_LEN + NONCE_LEN,
+            "credentials file is truncated"
+        );
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(&derive_key(&self.passphrase, salt)?);
+        let json = cipher
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| anyhow!("wrong passphrase, or the credentials file is corrupted"))?;
+        serde_json::from_slice(&json).context("invalid credentials file")
+    }
+
+    fn write(&self, secrets: &HashMap<String, String>) -> Result<()> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let cipher = Aes256Gcm::new(&derive_key(&self.passphrase, &salt)?);
+        let encrypted = cipher
+            .encrypt(&nonce, serde_json::to_vec(secrets)?.as_slice())
+            .map_err(|_| anyhow!("failed to encrypt credentials"))?;
+
+        let mut data = salt.to_vec();
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&encrypted);
+        write_atomic_private(&self.path, &data).context("write credentials file")
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from passphrase: {}", err))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_encrypted_file() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("zcode-bench-credentials-{}", std::process::id()));
+        let store = CredentialStore::File(EncryptedFile::new(path.clone(), "secret".into()));
+        assert_eq!(store.get("token")?, None);
+        store.set("token", "abc")?;
+        store.set("login", "{}")?;
+        store.delete("login")?;
+        assert_eq!(store.get("token")?.as_deref(), Some("abc"));
+        assert_eq!(store.get("login")?, None);
+        assert!(!String::from_utf8_lossy(&fs::read(&path)?).contains("abc"));
+
+        let wrong = EncryptedFile::new(path.clone(), "guess".into());
+        assert!(wrong.load().is_err());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -2,22 +2,39 @@ use std::{
     borrow::Cow,
     ffi::OsStr,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
 
+use futures::FutureExt;
 use path_slash::PathBufExt;
-use protocol::http::Response;
+use protocol::{http::Response, upload::ConflictPolicy};
 use serde::{Deserialize, Serialize};
-use tauri::Runtime;
+use tauri::{AppHandle, Manager, Runtime, State};
 
-use tracing::{debug, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
-    file_system::upload::UploadClient, get, my_err::MyResult, post, settings::RemoteServerConfig,
+    client::http_client,
+    file_system::{
+        download::DownloadClient,
+        upload::{UploadClient, UploadEvent},
+        upload_dir::{DirUploadClient, DirUploadEvent},
+    },
+    get, log_if_err,
+    my_err::MyResult,
+    post,
+    settings::{get_settings, RemoteServerConfig},
+    transfer::{TaskFn, TransferHandle, TransferInfo, TransferKind, TransferManager},
 };
 
+mod download;
+mod progress;
+#[cfg(test)]
+mod stand_in;
 mod upload;
+mod upload_dir;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct UnixPath(PathBuf);
@@ -51,10 +68,19 @@ pub struct FileNode {
     children: Option<Vec<FileNode>>,
 }
 
+impl FileNode {
+    fn is_dir(&self) -> bool {
+        self.children.is_some()
+    }
+}
+
 #[tauri::command]
 pub async fn load_dir_tree() -> MyResult<FileNode> {
     debug!("loading");
-    let tree: Response<FileNode> = get!(RemoteServerConfig::api_load_structure());
+    let tree: Response<FileNode> = get!(
+        client: http_client()?,
+        url: RemoteServerConfig::api_load_structure(),
+    );
     let tree = tree.to_result()?.expect("expect root file node");
     Ok(tree)
 }
@@ -63,8 +89,7 @@ pub async fn load_dir_tree() -> MyResult<FileNode> {
 #[tauri::command]
 pub async fn load_dir_content(path: PathBuf) -> MyResult<Vec<FileNode>> {
     debug!("loading");
-    let nodes: Response<Vec<FileNode>> =
-        get!(RemoteServerConfig::api_load_dir_content(), query: {"path": path});
+    let nodes: Response<Vec<FileNode>> = get!(client: http_client()?, url: RemoteServerConfig::api_load_dir_content(), query: {"path": path});
     let nodes = nodes.to_result()?.unwrap();
     Ok(nodes)
 }
@@ -74,7 +99,7 @@ pub async fn load_dir_content(path: PathBuf) -> MyResult<Vec<FileNode>> {
 pub async fn create_dir(path: &Path) -> MyResult<()> {
     debug!("creating dir");
     let url = RemoteServerConfig::url_create_dir();
-    let res: Response<()> = post!(url, body: {"path": path});
+    let res: Response<()> = post!(client: http_client()?, url: url, body: {"path": path});
     res.to_result()?;
 
     Ok(())
@@ -89,7 +114,7 @@ pub async fn move_to(from: &Path, to_dir: &Path) -> MyResult<()> {
     let to = to.to_slash_lossy();
 
     let url = RemoteServerConfig::url_move();
-    let res: Response<()> = post!(url, body: {"from": from, "to": to});
+    let res: Response<()> = post!(client: http_client()?, url: url, body: {"from": from, "to": to});
     res.to_result()?;
 
     Ok(())
@@ -99,26 +124,199 @@ pub async fn move_to(from: &Path, to_dir: &Path) -> MyResult<()> {
 pub async fn delete_file(path: &Path) -> MyResult<()> {
     debug!("deleting");
     let url = RemoteServerConfig::url_delete_file();
-    let res: Response<()> = post!(url, body: {"path": path});
+    let res: Response<()> = post!(client: http_client()?, url: url, body: {"path": path});
     res.to_result()?;
 
     Ok(())
 }
 
 #[tauri::command]
-pub async fn upload_file<R: Runtime>(
-    window: tauri::Window<R>,
+pub async fn upload_file(
+    transfers: State<'_, TransferManager>,
     local_path: PathBuf,
     to_dir: UnixPath,
-) -> MyResult<String> {
+    on_conflict: Option<ConflictPolicy>,
+) -> MyResult<TransferInfo> {
     info!(?local_path, ?to_dir, "uploading");
-    let dst = to_dir.join(get_file_name(&local_path)?);
-    let client = UploadClient::new(local_path, dst, window).await?;
-    let event_key = client.task_event_key.clone();
-    client.run();
+    let on_conflict = on_conflict.unwrap_or_default();
+    let info = enqueue_upload(
+        &transfers,
+        TransferKind::Upload,
+        &local_path,
+        &to_dir,
+        on_conflict,
+    )?;
+
+    debug!(?info);
+    Ok(info)
+}
+
+#[tauri::command]
+pub async fn upload_dir(
+    transfers: State<'_, TransferManager>,
+    local_path: PathBuf,
+    to_dir: UnixPath,
+    on_conflict: Option<ConflictPolicy>,
+) -> MyResult<TransferInfo> {
+    info!(?local_path, ?to_dir, "uploading dir");
+    let on_conflict = on_conflict.unwrap_or_default();
+    let info = enqueue_upload(
+        &transfers,
+        TransferKind::UploadDir,
+        &local_path,
+        &to_dir,
+        on_conflict,
+    )?;
+
+    debug!(?info);
+    Ok(info)
+}
+
+/// Queues the upload of a local file or dir into `to_dir`.
+fn enqueue_upload(
+    transfers: &TransferManager,
+    kind: TransferKind,
+    local_path: &Path,
+    to_dir: &UnixPath,
+    on_conflict: ConflictPolicy,
+) -> Result<TransferInfo> {
+    let dst = to_dir.join(get_file_name(local_path)?);
+    let (src, dst) = (
+        local_path.display().to_string(),
+        dst.to_string_lossy().to_string(),
+    );
+    let task = transfer_task(kind, &src, &dst, on_conflict);
+    Ok(transfers.spawn(kind, src, dst, on_conflict, task))
+}
+
+/// The remote dir opened in the file manager, where dropped files are uploaded.
+#[derive(Default)]
+pub struct CurrentDir(Mutex<Option<UnixPath>>);
 
-    debug!(%event_key);
-    Ok(event_key)
+#[tauri::command]
+pub fn set_current_dir(current: State<'_, CurrentDir>, path: UnixPath) {
+    debug!(?path, "current dir");
+    *current.0.lock().unwrap() = Some(path);
+}
+
+/// Uploads the files and dirs dropped on the window into the current dir.
+///
+/// Every queued upload is announced by an `upload-dropped` event carrying its
+/// [`TransferInfo`], so the frontend can subscribe to it.
+pub fn upload_dropped<R: Runtime>(app: &AppHandle<R>, paths: &[PathBuf]) {
+    let Some(to_dir) = app.state::<CurrentDir>().0.lock().unwrap().clone() else {
+        warn!(?paths, "files dropped before a remote dir is opened");
+        return;
+    };
+    let transfers = app.state::<TransferManager>();
+    for path in paths {
+        info!(?path, ?to_dir, "uploading dropped");
+        let kind = if path.is_dir() {
+            TransferKind::UploadDir
+        } else {
+            TransferKind::Upload
+        };
+        match enqueue_upload(&transfers, kind, path, &to_dir, ConflictPolicy::default()) {
+            Ok(info) => log_if_err!(app.emit_all("upload-dropped", info)),
+            Err(err) => error!(?err, ?path, "failed to upload dropped path"),
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn download_file(
+    transfers: State<'_, TransferManager>,
+    remote_path: UnixPath,
+    to_dir: PathBuf,
+) -> MyResult<TransferInfo> {
+    info!(?remote_path, ?to_dir, "downloading");
+    let dst = to_dir.join(get_file_name(&remote_path.0)?);
+    let (src, dst) = (
+        remote_path.to_string_lossy().to_string(),
+        dst.display().to_string(),
+    );
+    let on_conflict = ConflictPolicy::default();
+    let task = transfer_task(TransferKind::Download, &src, &dst, on_conflict);
+    let info = transfers.spawn(TransferKind::Download, src, dst, on_conflict, task);
+
+    debug!(?info);
+    Ok(info)
+}
+
+/// Builds the task doing a transfer from the paths recorded in its [`TransferInfo`].
+pub fn transfer_task(
+    kind: TransferKind,
+    src: &str,
+    dst: &str,
+    on_conflict: ConflictPolicy,
+) -> TaskFn {
+    let (src, dst) = (PathBuf::from(src), PathBuf::from(dst));
+    match kind {
+        TransferKind::Upload => Arc::new(move |handle: TransferHandle| {
+            // the server may have chosen another path when the transfer last ran
+            let dst = handle.dst().map_or_else(|| dst.clone(), PathBuf::from);
+            let (local_path, dst) = (src.clone(), UnixPath(dst));
+            handle.events.emit(UploadEvent::queued());
+            async move {
+                let mut client = UploadClient::new(
+                    local_path,
+                    dst,
+                    handle.events.clone(),
+                    handle.stop.clone(),
+                    handle.limits.clone(),
+                    on_conflict,
+                )
+                .await?;
+                let renamed = handle.clone();
+                client.on_dst(move |dst| renamed.set_dst(&dst));
+                client.on_confirmed(move |bytes| handle.set_confirmed(bytes));
+                client.runn_inner().await
+            }
+            .boxed()
+        }),
+        TransferKind::UploadDir => Arc::new(move |handle: TransferHandle| {
+            let (local_path, dst) = (src.clone(), UnixPath(dst.clone()));
+            handle.events.emit(DirUploadEvent::queued());
+            async move {
+                let mut client = DirUploadClient::new(
+                    local_path,
+                    dst,
+                    handle.events.clone(),
+                    handle.stop.clone(),
+                    handle.limits.clone(),
+                    on_conflict,
+                )
+                .await?;
+                client.set_confirmed(handle.confirmed());
+                client.on_confirmed(move |bytes| handle.set_confirmed(bytes));
+                client.runn_inner().await
+            }
+            .boxed()
+        }),
+        TransferKind::Download => Arc::new(move |handle: TransferHandle| {
+            let (remote_path, dst) = (UnixPath(src.clone()), dst.clone());
+            async move {
+                let client = DownloadClient::new(
+                    remote_path,
+                    dst,
+                    handle.events.clone(),
+                    handle.stop.clone(),
+                )
+                .await?;
+                client.runn_inner().await
+            }
+            .boxed()
+        }),
+    }
+}
+
+/// Registers the transfers saved by a previous run of the app.
+pub fn restore_transfers(transfers: &TransferManager) {
+    let resume = get_settings().transfer.resume_on_startup;
+    for saved in transfers.load_saved() {
+        let task = transfer_task(saved.kind, &saved.src, &saved.dst, saved.on_conflict);
+        transfers.restore(saved, task, resume);
+    }
 }
 
 fn get_file_name(path: &Path) -> Result<&OsStr> {
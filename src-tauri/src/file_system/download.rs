@@ -0,0 +1,146 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use protocol::{
+    download::{ClientCodec, DownloadRequest, DownloadResponse},
+    register_client::ClientType,
+};
+use serde::Serialize;
+use tokio::{fs::File, io::AsyncWriteExt};
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    client::{self, ClientStream},
+    transfer::{StopSignal, TransferEvents},
+};
+
+use super::{
+    progress::{Progress, ProgressMeter},
+    UnixPath,
+};
+
+pub struct DownloadClient {
+    src_path: UnixPath,
+    local_path: PathBuf,
+    size: u64,
+    framed: Framed<ClientStream, ClientCodec>,
+    events: TransferEvents,
+    stop: StopSignal,
+}
+
+impl DownloadClient {
+    pub async fn new(
+        src: UnixPath,
+        dst: PathBuf,
+        events: TransferEvents,
+        stop: StopSignal,
+    ) -> anyhow::Result<Self> {
+        let framed = client::build_client_frame(ClientCodec::new(), ClientType::Download)
+            .await
+            .context("connect server")?;
+        let mut this = Self {
+            src_path: src,
+            local_path: dst,
+            size: 0,
+            framed,
+            events,
+            stop,
+        };
+        this.handshake().await?;
+        Ok(this)
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        self.framed
+            .send(DownloadRequest::Register(
+                self.src_path.to_string_lossy().to_string(),
+            ))
+            .await?;
+        match self.framed.next().await {
+            Some(Ok(DownloadResponse::RegisterResult(Some(size)))) => {
+                self.size = size;
+            }
+            Some(Ok(DownloadResponse::RegisterResult(None))) => {
+                bail!("server rejected download client handshake")
+            }
+            Some(Ok(_)) => {
+                bail!("unexpected server handshake msg")
+            }
+            Some(Err(err)) => {
+                error!(?err);
+                bail!("failed to decode server handshake msg: {}", err)
+            }
+            None => {
+                bail!("server didn't send handshake result")
+            }
+        }
+        debug!(size = self.size, "handshake ok");
+        Ok(())
+    }
+
+    pub(super) async fn runn_inner(mut self) -> Result<()> {
+        debug!(?self.src_path, ?self.local_path, "receiving file");
+        let mut file = File::create(&self.local_path)
+            .await
+            .with_context(|| format!("create local file: {}", self.local_path.display()))?;
+
+        let mut write_size = 0;
+        let mut meter = ProgressMeter::new(self.size, 0);
+        self.events.emit(DownloadEvent {
+            progress: meter.progress(),
+        });
+
+        loop {
+            if let Err(err) = self.stop.check() {
+                drop(file);
+                // a download starts over on resume, so the partial file is of no use
+                if let Err(err) = tokio::fs::remove_file(&self.local_path).await {
+                    warn!(?err, "failed to remove partial file");
+                }
+                return Err(err);
+            }
+
+            let chunk = match self.framed.next().await {
+                Some(Ok(DownloadResponse::Download(chunk))) => chunk,
+                Some(Ok(DownloadResponse::Eof)) => break,
+                Some(Ok(DownloadResponse::RegisterResult(_))) => {
+                    bail!("unexpected register msg during download")
+                }
+                Some(Err(err)) => {
+                    bail!("failed to decode server msg: {}", err)
+                }
+                None => {
+                    bail!("server closed connection before the file was complete")
+                }
+            };
+
+            file.write_all(&chunk).await?;
+            write_size += chunk.len() as u64;
+
+            if let Some(progress) = meter.update(write_size) {
+                self.events.emit(DownloadEvent { progress });
+            }
+        }
+        file.flush().await?;
+
+        if write_size != self.size {
+            bail!(
+                "file size mismatch! expect {}, received {}",
+                self.size,
+                write_size
+            );
+        }
+
+        info!("receive file done");
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct DownloadEvent {
+    #[serde(flatten)]
+    progress: Progress,
+}
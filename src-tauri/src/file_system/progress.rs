@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// min interval between two progress events of a transfer
+const EMIT_INTERVAL: Duration = Duration::from_millis(200);
+/// weight of the latest sample in the smoothed rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Progress {
+    pub sent_bytes: u64,
+    pub total_bytes: u64,
+    pub percent: f64,
+    /// bytes per second, smoothed over the last samples
+    pub rate: u64,
+    /// bytes per second since the transfer started
+    pub avg_rate: u64,
+    /// seconds left at the current rate, unknown until some bytes are sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+}
+
+/// Computes throughput and ETA from the bytes sent so far, and limits how often
+/// progress is reported to the frontend.
+pub struct ProgressMeter {
+    progress: Progress,
+    started: Instant,
+    /// bytes already there when the meter started, not counted in the average rate
+    start_bytes: u64,
+    last_sample: Instant,
+    last_bytes: u64,
+    last_emit: Option<Instant>,
+}
+
+impl ProgressMeter {
+    pub fn new(total_bytes: u64, start_bytes: u64) -> Self {
+        Self::new_at(total_bytes, start_bytes, Instant::now())
+    }
+
+    fn new_at(total_bytes: u64, start_bytes: u64, now: Instant) -> Self {
+        let mut this = Self {
+            progress: Progress {
+                total_bytes,
+                ..Default::default()
+            },
+            started: now,
+            start_bytes,
+            last_sample: now,
+            last_bytes: start_bytes,
+            last_emit: None,
+        };
+        this.set_sent(start_bytes);
+        this
+    }
+
+    /// Records the bytes sent so far, returns the progress if it is time to report it.
+    pub fn update(&mut self, sent_bytes: u64) -> Option<Progress> {
+        self.update_at(sent_bytes, Instant::now())
+    }
+
+    fn update_at(&mut self, sent_bytes: u64, now: Instant) -> Option<Progress> {
+        self.set_sent(sent_bytes);
+
+        let finished = sent_bytes >= self.progress.total_bytes;
+        let due = match self.last_emit {
+            Some(last) => now.duration_since(last) >= EMIT_INTERVAL,
+            None => true,
+        };
+        if !due && !finished {
+            return None;
+        }
+        self.last_emit = Some(now);
+        self.sample(now);
+        Some(self.progress.clone())
+    }
+
+    /// The latest progress, regardless of when it was last reported.
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    fn set_sent(&mut self, sent_bytes: u64) {
+        let progress = &mut self.progress;
+        progress.sent_bytes = sent_bytes;
+        progress.percent = if progress.total_bytes == 0 {
+            100.0
+        } else {
+            sent_bytes as f64 / progress.total_bytes as f64 * 100.0
+        };
+    }
+
+    fn sample(&mut self, now: Instant) {
+        let sent_bytes = self.progress.sent_bytes;
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+        if elapsed > 0.0 {
+            // bytes may be sent again after a reconnect, so `sent_bytes` can go back
+            let rate = sent_bytes.saturating_sub(self.last_bytes) as f64 / elapsed;
+            self.progress.rate = if self.progress.rate == 0 {
+                rate as u64
+            } else {
+                (RATE_SMOOTHING * rate + (1.0 - RATE_SMOOTHING) * self.progress.rate as f64) as u64
+            };
+        }
+        self.last_sample = now;
+        self.last_bytes = sent_bytes;
+
+        let total_elapsed = now.duration_since(self.started).as_secs_f64();
+        if total_elapsed > 0.0 {
+            let sent = sent_bytes.saturating_sub(self.start_bytes);
+            self.progress.avg_rate = (sent as f64 / total_elapsed) as u64;
+        }
+
+        let left = self.progress.total_bytes.saturating_sub(sent_bytes);
+        self.progress.eta_secs = match self.progress.rate {
+            _ if left == 0 => Some(0),
+            0 => None,
+            rate => Some(left.div_ceil(rate)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_rate_eta_and_throttle() {
+        let start = Instant::now();
+        let mut meter = ProgressMeter::new_at(1000, 100, start);
+
+        let first = meter
+            .update_at(200, start + Duration::from_secs(1))
+            .expect("first update is reported");
+        assert_eq!(first.rate, 100);
+        assert_eq!(first.avg_rate, 100);
+        assert_eq!(first.eta_secs, Some(8));
+        assert_eq!(first.percent, 20.0);
+
+        // too soon after the last report
+        assert!(meter
+            .update_at(210, start + Duration::from_millis(1050))
+            .is_none());
+        assert_eq!(meter.progress().sent_bytes, 210);
+
+        let second = meter
+            .update_at(400, start + Duration::from_secs(2))
+            .expect("reported after the interval");
+        assert_eq!(second.rate, 130);
+        assert_eq!(second.avg_rate, 150);
+
+        // the last bytes are always reported
+        let last = meter
+            .update_at(1000, start + Duration::from_millis(2010))
+            .expect("finished");
+        assert_eq!(last.eta_secs, Some(0));
+        assert_eq!(last.percent, 100.0);
+    }
+}
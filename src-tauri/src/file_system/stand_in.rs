@@ -0,0 +1,291 @@
+//! A local stand-in for the server, for the tests of the uploads.
+//!
+//! It keeps the uploaded files in memory, and answers the http api with
+//! success, so remote dirs can always be created.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use protocol::{
+    register_client::{self, RegisterClientReq, RegisterResult},
+    upload::{self, ConflictPolicy, FileIdentity, UploadRequest, UploadResponse},
+    Codec,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    auth, profile, settings,
+    transfer::{EmitFn, TransferId, TransferInfo, TransferManager, TransferStatus},
+};
+
+#[derive(Default)]
+pub struct StandIn {
+    files: Mutex<Files>,
+}
+
+#[derive(Default)]
+struct Files {
+    /// content of the committed files by path
+    committed: HashMap<String, Vec<u8>>,
+    /// partial uploads by path
+    partial: HashMap<String, (FileIdentity, Vec<u8>)>,
+    /// offsets answered to the registrations of every path, in order
+    offsets: HashMap<String, Vec<u64>>,
+    /// the next connection uploading to the path is dropped once it received
+    /// that many bytes
+    drop_at: HashMap<String, usize>,
+}
+
+/// The stand-in server, started with the settings and the profiles pointing to
+/// it on first use.
+pub fn stand_in() -> &'static StandIn {
+    static STAND_IN: OnceLock<&'static StandIn> = OnceLock::new();
+    STAND_IN.get_or_init(|| {
+        let server: &'static StandIn = Box::leak(Box::default());
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let overrides = format!(
+            "[remote_server]\nhttp = \"{}\"\ntcp = \"{}\"\n[upload]\nchunk_size = 16384\n",
+            http.local_addr().unwrap(),
+            tcp.local_addr().unwrap()
+        );
+        let settings = settings::load_for_test(&overrides).unwrap();
+        profile::init(&settings, None);
+
+        // on its own runtime, as every test has one which ends with it
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                // there is no credential store, it only lets the requests through
+                auth::restore_session().await;
+                tcp.set_nonblocking(true).unwrap();
+                http.set_nonblocking(true).unwrap();
+                let tcp = TcpListener::from_std(tcp).unwrap();
+                let http = TcpListener::from_std(http).unwrap();
+                tokio::spawn(async move {
+                    while let Ok((stream, _)) = http.accept().await {
+                        tokio::spawn(answer_http(stream));
+                    }
+                });
+                while let Ok((stream, _)) = tcp.accept().await {
+                    tokio::spawn(async move {
+                        if let Err(err) = server.serve(stream).await {
+                            tracing::debug!(?err, "stand-in connection closed");
+                        }
+                    });
+                }
+            });
+        });
+        server
+    })
+}
+
+impl StandIn {
+    /// Puts a file on the server, as if it was uploaded before.
+    pub fn commit(&self, path: &str, content: &[u8]) {
+        let mut files = self.files.lock().unwrap();
+        files.committed.insert(path.to_string(), content.to_vec());
+    }
+
+    pub fn committed(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().committed.get(path).cloned()
+    }
+
+    /// Bytes received for the upload to `path` which is not committed yet.
+    pub fn received(&self, path: &str) -> usize {
+        let files = self.files.lock().unwrap();
+        files.partial.get(path).map_or(0, |(_, data)| data.len())
+    }
+
+    /// Offsets answered to the registrations of `path`, in order.
+    pub fn offsets(&self, path: &str) -> Vec<u64> {
+        let files = self.files.lock().unwrap();
+        files.offsets.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Drops the next connection uploading to `path` once it received `bytes`.
+    pub fn drop_at(&self, path: &str, bytes: usize) {
+        let mut files = self.files.lock().unwrap();
+        files.drop_at.insert(path.to_string(), bytes);
+    }
+
+    async fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut framed = register_client::ServerCodec::new().framed(stream);
+        let RegisterClientReq::SwitchProtocol(_, encoding, _) =
+            framed.next().await.context("no switch msg")??;
+        framed.send(RegisterResult::Ok(encoding)).await?;
+        let mut codec = upload::ServerCodec::new();
+        codec.set_encoding(encoding);
+        let mut framed = Framed::new(framed.into_inner(), codec);
+
+        let mut path = None;
+        let mut drop_at = None;
+        let mut received = 0;
+        while let Some(req) = framed.next().await {
+            let resp = match req? {
+                UploadRequest::Register {
+                    path: registered,
+                    identity,
+                    on_conflict,
+                } => {
+                    let (resp, upload_to) = self.register(registered, identity, on_conflict);
+                    drop_at = upload_to
+                        .as_ref()
+                        .and_then(|path| self.files.lock().unwrap().drop_at.remove(path));
+                    path = upload_to;
+                    resp
+                }
+                UploadRequest::Upload(data) => {
+                    let path = path.as_ref().context("upload before register")?;
+                    received += data.len();
+                    let mut files = self.files.lock().unwrap();
+                    let (_, partial) = files.partial.get_mut(path).context("no partial file")?;
+                    partial.extend_from_slice(&data);
+                    if drop_at.is_some_and(|at| received >= at) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                UploadRequest::Negotiate { .. } => UploadResponse::Negotiated {
+                    compression: Default::default(),
+                },
+                UploadRequest::Verify(hash) => {
+                    let path = path.as_ref().context("verify before register")?;
+                    let files = self.files.lock().unwrap();
+                    let (_, partial) = files.partial.get(path).context("no partial file")?;
+                    UploadResponse::VerifyResult(format!("{:x}", Sha256::digest(partial)) == hash)
+                }
+                UploadRequest::Finish => {
+                    let path = path.clone().context("finish before register")?;
+                    let mut files = self.files.lock().unwrap();
+                    let (_, data) = files.partial.remove(&path).context("no partial file")?;
+                    let size = data.len() as u64;
+                    files.committed.insert(path.clone(), data);
+                    UploadResponse::Committed { size, path }
+                }
+                UploadRequest::Cancel { keep_partial } => {
+                    if let (Some(path), false) = (&path, keep_partial) {
+                        self.files.lock().unwrap().partial.remove(path);
+                    }
+                    return Ok(());
+                }
+                _ => bail!("not supported by the stand-in"),
+            };
+            framed.send(resp).await?;
+        }
+        Ok(())
+    }
+
+    /// Answers the registration of an upload, with the path the data goes to.
+    fn register(
+        &self,
+        path: String,
+        identity: FileIdentity,
+        on_conflict: ConflictPolicy,
+    ) -> (UploadResponse, Option<String>) {
+        let mut files = self.files.lock().unwrap();
+        let taken = |files: &Files, path: &str| {
+            files.committed.contains_key(path) || files.partial.contains_key(path)
+        };
+        let path = match on_conflict {
+            _ if !files.committed.contains_key(&path) => path,
+            ConflictPolicy::Overwrite => path,
+            ConflictPolicy::Skip => return (UploadResponse::Skipped, None),
+            ConflictPolicy::Fail => return (UploadResponse::Conflict, None),
+            ConflictPolicy::Rename => {
+                let renamed = (1..)
+                    .map(|i| format!("{} ({})", path, i))
+                    .find(|renamed| !taken(&files, renamed))
+                    .unwrap();
+                files.partial.insert(renamed.clone(), (identity, vec![]));
+                files.offsets.entry(renamed.clone()).or_default().push(0);
+                return (
+                    UploadResponse::Renamed {
+                        path: renamed.clone(),
+                        offset: 0,
+                    },
+                    Some(renamed),
+                );
+            }
+        };
+
+        let partial = files
+            .partial
+            .entry(path.clone())
+            .or_insert_with(|| (identity.clone(), vec![]));
+        if partial.0 != identity {
+            *partial = (identity, vec![]);
+        }
+        let offset = partial.1.len() as u64;
+        files.offsets.entry(path.clone()).or_default().push(offset);
+        (UploadResponse::RegisterResult(Some(offset)), Some(path))
+    }
+}
+
+/// Answers any request of the http api with success.
+async fn answer_http(mut stream: TcpStream) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    // the body is small, and sent along with the head
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+    let body = r#"{"status":0}"#;
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// A manager whose events go nowhere, with `rate` bytes per second for every
+/// transfer.
+pub fn transfers(rate: Option<u64>) -> TransferManager {
+    let emit: EmitFn = Arc::new(|_: &str, _: &serde_json::Value| {});
+    TransferManager::new(1, None, emit).with_rate_limits(None, rate)
+}
+
+/// Waits until the transfer is in `status`, panics if it ends otherwise.
+pub async fn wait_status(
+    transfers: &TransferManager,
+    id: TransferId,
+    status: TransferStatus,
+) -> TransferInfo {
+    for _ in 0..400 {
+        let info = transfers.get(id).unwrap();
+        if info.status == status {
+            return info;
+        }
+        if let TransferStatus::Done | TransferStatus::Failed | TransferStatus::Cancelled =
+            info.status
+        {
+            panic!("transfer ended before reaching {:?}: {:?}", status, info);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!(
+        "transfer never reached {:?}: {:?}",
+        status,
+        transfers.get(id)
+    );
+}
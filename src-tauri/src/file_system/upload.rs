@@ -1,147 +1,829 @@
-use std::{path::PathBuf, sync::atomic::AtomicU32, time::Duration};
+use std::{
+    ffi::OsStr,
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
 
 use anyhow::{bail, ensure, Context, Result};
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protocol::{
+    compression::Compression,
     register_client::ClientType,
-    upload::{ClientCodec, UploadRequest},
+    upload::{ClientCodec, ConflictPolicy, FileIdentity, UploadRequest, UploadResponse},
 };
 use serde::Serialize;
-use tauri::{Runtime, Window};
-use tokio::{fs::File, io::AsyncReadExt, net::TcpStream};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
 use tokio_util::codec::Framed;
 use tracing::{debug, error, info, warn};
 
-use crate::{client, log_if_err};
+use crate::{
+    client::{self, ClientStream},
+    my_err::DestinationExists,
+    rate_limit::RateLimits,
+    settings::get_settings,
+    transfer::{Stop, StopSignal, Stopped, TransferEvents},
+};
+
+use super::{
+    progress::{Progress, ProgressMeter},
+    UnixPath,
+};
 
-use super::UnixPath;
+/// how many times in a row we try to reconnect without making any progress
+const MAX_RETRIES: u32 = 5;
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(30);
+/// extensions of files whose content is already compressed
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "mov", "avi", "webm", "flv", "ts", "h264", "h265", "hevc", "av1", "jpg", "jpeg",
+    "png", "gif", "webp", "heic", "mp3", "aac", "m4a", "ogg", "opus", "flac", "zip", "gz", "tgz",
+    "bz2", "xz", "zst", "lz4", "7z", "rar",
+];
 
-pub struct UploadClient<R: Runtime> {
-    pub task_event_key: String,
+pub struct UploadClient {
     local_path: PathBuf,
     dst_path: UnixPath,
-    framed: Framed<TcpStream, ClientCodec>,
-    window: Window<R>,
+    identity: FileIdentity,
+    /// bytes the server already holds
+    offset: u64,
+    on_conflict: ConflictPolicy,
+    /// the destination exists and is kept, nothing is uploaded
+    skipped: bool,
+    /// codec offered to the server, none for files already compressed
+    offer: Compression,
+    /// codec of the chunks sent over the current connection
+    compression: Compression,
+    framed: Framed<ClientStream, ClientCodec>,
+    events: TransferEvents,
+    meter: ProgressMeter,
+    /// called with the bytes of the file read so far
+    on_progress: Option<Box<dyn FnMut(u64) + Send>>,
+    /// called with the bytes the server confirmed to hold
+    on_confirmed: Option<Box<dyn FnMut(u64) + Send>>,
+    /// called with the path the file is uploaded to, which the server changes
+    /// when the destination exists
+    on_dst: Option<Box<dyn FnMut(String) + Send>>,
+    stop: StopSignal,
+    limits: RateLimits,
 }
 
-impl<R: Runtime> UploadClient<R> {
+impl UploadClient {
     pub async fn new(
         src: PathBuf,
         dst: UnixPath,
-        window: tauri::Window<R>,
+        events: TransferEvents,
+        stop: StopSignal,
+        limits: RateLimits,
+        on_conflict: ConflictPolicy,
     ) -> anyhow::Result<Self> {
-        let framed = client::build_client_frame(ClientCodec::new(), ClientType::Upload)
-            .await
-            .context("connect server")?;
-        let mut this = Self {
+        let identity = file_identity(&src).await?;
+        let offer = compression_for(&src);
+        let (framed, registered, compression) =
+            Self::connect(&dst, &identity, on_conflict, offer).await?;
+        let (dst, offset, skipped) = match registered {
+            Registered::At(offset) => (dst, offset, false),
+            Registered::Renamed { path, offset } => {
+                info!(?dst, %path, "destination exists, uploading to another path");
+                (UnixPath(PathBuf::from(path)), offset, false)
+            }
+            Registered::Skipped => {
+                info!(?dst, "destination exists, skipping");
+                (dst, 0, true)
+            }
+            Registered::Conflict => {
+                let path = dst.to_string_lossy().to_string();
+                return Err(DestinationExists { path }.into());
+            }
+        };
+        Ok(Self {
             local_path: src,
             framed,
-            window,
-            task_event_key: next_event_key(),
+            events,
             dst_path: dst,
+            meter: ProgressMeter::new(identity.size, offset),
+            identity,
+            offset,
+            on_conflict,
+            skipped,
+            offer,
+            compression,
+            on_progress: None,
+            on_confirmed: None,
+            on_dst: None,
+            stop,
+            limits,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.identity.size
+    }
+
+    pub fn on_progress(&mut self, f: impl FnMut(u64) + Send + 'static) {
+        self.on_progress = Some(Box::new(f));
+    }
+
+    pub fn on_confirmed(&mut self, f: impl FnMut(u64) + Send + 'static) {
+        self.on_confirmed = Some(Box::new(f));
+    }
+
+    fn confirmed(&mut self, bytes: u64) {
+        if let Some(on_confirmed) = &mut self.on_confirmed {
+            on_confirmed(bytes);
+        }
+    }
+
+    pub fn on_dst(&mut self, f: impl FnMut(String) + Send + 'static) {
+        self.on_dst = Some(Box::new(f));
+    }
+
+    fn dst_chosen(&mut self) {
+        if let Some(on_dst) = &mut self.on_dst {
+            on_dst(self.dst_path.to_string_lossy().to_string());
+        }
+    }
+
+    async fn connect(
+        dst: &UnixPath,
+        identity: &FileIdentity,
+        on_conflict: ConflictPolicy,
+        offer: Compression,
+    ) -> Result<(Framed<ClientStream, ClientCodec>, Registered, Compression)> {
+        let mut framed = client::build_client_frame(ClientCodec::new(), ClientType::Upload)
+            .await
+            .context("connect server")?;
+        let register = UploadRequest::Register {
+            path: dst.to_string_lossy().to_string(),
+            identity: identity.clone(),
+            on_conflict,
         };
-        this.handshake().await?;
-        Ok(this)
+        let registered = handshake(&mut framed, register, identity.size).await?;
+        let compression = match registered {
+            Registered::At(_) | Registered::Renamed { .. } => negotiate(&mut framed, offer).await?,
+            Registered::Skipped | Registered::Conflict => Compression::None,
+        };
+        Ok((framed, registered, compression))
     }
 
-    async fn handshake(&mut self) -> Result<()> {
-        self.framed
-            .send(UploadRequest::Register(
-                self.dst_path.to_string_lossy().to_string(),
-            ))
-            .await?;
-        match self.framed.next().await {
-            Some(Ok(msg)) => match msg {
-                protocol::upload::UploadResponse::RegisterResult(ok) => {
-                    ensure!(ok, "server rejected upload client handshake")
-                }
-            },
-            Some(Err(err)) => {
-                error!(?err);
-                bail!("failed to decode server handshake msg: {}", err)
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                reason = self.stop.stopped() => return Err(Stopped(reason).into()),
             }
-            None => {
-                bail!("server didn't send handshake result")
+            let connected =
+                Self::connect(&self.dst_path, &self.identity, self.on_conflict, self.offer).await;
+            match connected {
+                Ok((framed, registered, compression)) => {
+                    self.framed = framed;
+                    self.compression = compression;
+                    self.offset = match registered {
+                        Registered::At(offset) => offset,
+                        Registered::Renamed { path, offset } => {
+                            info!(%path, "destination exists, uploading to another path");
+                            self.dst_path = UnixPath(PathBuf::from(path));
+                            self.dst_chosen();
+                            offset
+                        }
+                        Registered::Skipped | Registered::Conflict => {
+                            bail!("destination was created during the upload")
+                        }
+                    };
+                    return Ok(());
+                }
+                Err(err) if retries < MAX_RETRIES => {
+                    retries += 1;
+                    warn!(?err, retries, "reconnect failed");
+                }
+                Err(err) => return Err(err.context("reconnect server")),
             }
         }
-        debug!("handshake ok");
-        Ok(())
     }
 
-    pub fn run(self) {
-        tokio::spawn(async move { log_if_err!(self.runn_inner().await) });
-    }
-
-    async fn runn_inner(mut self) -> Result<()> {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        let win_name = self.window.label();
+    pub(super) async fn runn_inner(mut self) -> Result<()> {
+        debug!(?self.local_path, "sending file");
+        if self.skipped {
+            self.emit(UploadState::Skipped);
+            return Ok(());
+        }
 
-        debug!(?self.local_path, %win_name, "sending file");
+        // the server keeps what it got under the path it chose, even if stopped now
+        self.dst_chosen();
+        self.check_stop().await?;
+        self.confirmed(self.offset);
+        self.emit(UploadState::Running);
         let mut file = File::open(&self.local_path).await?;
-        let size = file.metadata().await?.len();
 
-        let mut bytes = BytesMut::with_capacity(500);
+        let mut retries = 0;
+        let (read_size, hash) = loop {
+            let offset = self.offset;
+            let err = match self.send_rest(&mut file).await {
+                Ok(sent) => break sent,
+                Err(err) if err.is::<Stopped>() => return Err(err),
+                Err(err) => err,
+            };
+            warn!(?err, "upload interrupted, reconnecting");
+            self.reconnect().await?;
+            self.confirmed(self.offset);
+
+            if self.offset > offset {
+                retries = 0;
+            } else {
+                retries += 1;
+                ensure!(
+                    retries <= MAX_RETRIES,
+                    "upload makes no progress: {:?}",
+                    err
+                );
+            }
+            info!(offset = self.offset, "resuming upload");
+        };
+
+        ensure!(
+            read_size == self.identity.size,
+            "file size changed during upload. expect {}, read {}",
+            self.identity.size,
+            read_size
+        );
+
+        self.emit(UploadState::Verifying);
+        ensure!(self.verify(hash).await?, "file verification failed");
+
+        self.emit(UploadState::Committing);
+        self.commit()
+            .await
+            .context("server did not commit the file")?;
+
+        info!("send file done");
+        Ok(())
+    }
+
+    fn emit(&self, state: UploadState) {
+        let event = UploadEvent {
+            state,
+            progress: self.meter.progress(),
+        };
+        self.events.emit(event);
+    }
+
+    /// Sends the rest of the file, starting at the offset confirmed by the server.
+    ///
+    /// Bytes already on the server are read again to feed the hasher, so the
+    /// returned hash always covers the whole file.
+    async fn send_from_offset(&mut self, file: &mut File) -> Result<(u64, String)> {
+        let chunk_size = get_settings().upload.chunk_size;
+        let mut hasher = Sha256::new();
         let mut read_size = 0;
 
+        file.seek(SeekFrom::Start(0)).await?;
+        if self.offset > 0 {
+            debug!(self.offset, "hashing bytes already on server");
+            let mut bytes = BytesMut::with_capacity(chunk_size);
+            let mut prefix = file.take(self.offset);
+            loop {
+                let len = prefix.read_buf(&mut bytes).await?;
+                if len == 0 {
+                    break;
+                }
+                read_size += len as u64;
+                hasher.update(&bytes);
+                bytes.clear();
+            }
+            ensure!(
+                read_size == self.offset,
+                "local file is shorter than expected"
+            );
+        }
+
         loop {
+            self.check_stop().await?;
+            // a fresh buffer for every chunk, so it can be handed to the codec without copying
+            let mut bytes = BytesMut::with_capacity(chunk_size);
             let len = file.read_buf(&mut bytes).await?;
             if len == 0 {
                 break;
             }
-            read_size += len;
+            read_size += len as u64;
+            hasher.update(&bytes);
+            let data = compress(self.compression, bytes.freeze()).await?;
+
+            tokio::select! {
+                _ = self.limits.acquire(data.len() as u64) => {}
+                // the wait may be long under a low limit
+                _ = self.stop.stopped() => {}
+            }
+            self.check_stop().await?;
 
             // send to server
-            self.framed
-                .send(UploadRequest::Upload(bytes.to_vec()))
-                .await?;
+            self.framed.send(UploadRequest::Upload(data)).await?;
 
-            let percent = format!("{:.02}", read_size as f64 / size as f64 * 100.0);
-            // nofity frontend
-            self.window
-                .emit(
-                    &self.task_event_key,
-                    UploadEvent {
-                        percent,
-                        is_done: false,
-                    },
-                )
-                .unwrap();
+            self.progress(read_size);
+        }
+        self.framed.flush().await?;
 
-            bytes.clear();
+        Ok((read_size, format!("{:x}", hasher.finalize())))
+    }
+
+    /// Sends the bytes the server does not hold yet, over several connections
+    /// if there are enough of them.
+    async fn send_rest(&mut self, file: &mut File) -> Result<(u64, String)> {
+        let settings = &get_settings().upload;
+        let left = self.identity.size - self.offset;
+        if settings.parallel_streams > 1 && left >= settings.parallel_min_size {
+            self.send_parallel(settings.parallel_streams).await
+        } else {
+            self.send_from_offset(file).await
         }
+    }
 
-        self.window
-            .emit(
-                &self.task_event_key,
-                UploadEvent {
-                    percent: "100".to_string(),
-                    is_done: true,
-                },
-            )
-            .unwrap();
+    /// Sends the rest of the file in `streams` parts at the same time, each over
+    /// its own connection.
+    ///
+    /// The hash is computed by reading the whole file once more along the parts.
+    async fn send_parallel(&mut self, streams: usize) -> Result<(u64, String)> {
+        let chunk_size = get_settings().upload.chunk_size;
+        let ranges = split_range(self.offset..self.identity.size, streams, chunk_size as u64);
+        debug!(?ranges, "sending parts in parallel");
+
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let parts: Vec<_> = ranges
+            .into_iter()
+            .map(|range| {
+                let part = Part {
+                    local_path: self.local_path.clone(),
+                    dst_path: self.dst_path.clone(),
+                    identity: self.identity.clone(),
+                    range,
+                    chunk_size,
+                    offer: self.offer,
+                    limits: self.limits.clone(),
+                    stop: self.stop.clone(),
+                    sent: sent_tx.clone(),
+                };
+                part.send()
+            })
+            .collect();
+        drop(sent_tx);
+        let parts = futures::future::try_join_all(parts);
+        let local_path = self.local_path.clone();
+        let hash = hash_file(&local_path, chunk_size);
+        let all = async { tokio::try_join!(hash, parts) };
+        tokio::pin!(all);
+
+        let mut read_size = self.offset;
+        let res = loop {
+            tokio::select! {
+                res = &mut all => break res,
+                Some(len) = sent_rx.recv() => {
+                    read_size += len;
+                    self.progress(read_size);
+                }
+            }
+        };
+        let (hashed, _) = match res {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                // tells the server to keep or discard the parts if stopped
+                self.check_stop().await?;
+                return Err(err);
+            }
+        };
+        while let Ok(len) = sent_rx.try_recv() {
+            read_size += len;
+        }
+        self.progress(read_size);
+        Ok(hashed)
+    }
 
-        if read_size as u64 != size {
-            warn!("file size mismatch!");
+    /// Notifies the frontend of the bytes sent so far.
+    fn progress(&mut self, read_size: u64) {
+        if let Some(progress) = self.meter.update(read_size) {
+            let event = UploadEvent {
+                state: UploadState::Running,
+                progress,
+            };
+            self.events.emit(event);
         }
+        if let Some(on_progress) = &mut self.on_progress {
+            on_progress(read_size);
+        }
+    }
 
-        info!("send file done");
+    /// Tells the server what to do with the partial file if the upload has been stopped.
+    async fn check_stop(&mut self) -> Result<()> {
+        let Some(reason) = self.stop.reason() else {
+            return Ok(());
+        };
+        info!(?reason, offset = self.offset, "upload stopped");
+        let keep_partial = reason == Stop::Pause;
+        if let Err(err) = self
+            .framed
+            .send(UploadRequest::Cancel { keep_partial })
+            .await
+        {
+            warn!(?err, "failed to tell server the upload is stopped");
+        }
+        Err(Stopped(reason).into())
+    }
+
+    async fn verify(&mut self, hash: String) -> Result<bool> {
+        debug!(%hash, "verifying");
+        self.framed.send(UploadRequest::Verify(hash)).await?;
+        match self.framed.next().await {
+            Some(Ok(UploadResponse::VerifyResult(ok))) => Ok(ok),
+            Some(Ok(_)) => bail!("unexpected server msg while verifying"),
+            Some(Err(err)) => bail!("failed to decode server verify msg: {}", err),
+            None => bail!("server closed connection before verify result"),
+        }
+    }
 
+    async fn commit(&mut self) -> Result<()> {
+        self.framed.send(UploadRequest::Finish).await?;
+        let ack = tokio::time::timeout(COMMIT_TIMEOUT, self.framed.next())
+            .await
+            .context("wait for commit ack timeout")?;
+        let (size, path) = match ack {
+            Some(Ok(UploadResponse::Committed { size, path })) => (size, path),
+            Some(Ok(_)) => bail!("unexpected server msg while committing"),
+            Some(Err(err)) => bail!("failed to decode server commit msg: {}", err),
+            None => bail!("server closed connection before commit ack"),
+        };
+        ensure!(
+            size == self.identity.size,
+            "server committed {} bytes, expect {}",
+            size,
+            self.identity.size
+        );
+        info!(size, %path, "server committed file");
+        self.confirmed(size);
         Ok(())
     }
 }
 
+/// A range of a file sent over its own connection.
+struct Part {
+    local_path: PathBuf,
+    dst_path: UnixPath,
+    identity: FileIdentity,
+    range: Range<u64>,
+    chunk_size: usize,
+    offer: Compression,
+    limits: RateLimits,
+    stop: StopSignal,
+    /// bytes sent by the part are reported here
+    sent: mpsc::UnboundedSender<u64>,
+}
+
+impl Part {
+    async fn send(self) -> Result<()> {
+        let mut framed = client::build_client_frame(ClientCodec::new(), ClientType::Upload)
+            .await
+            .context("connect server")?;
+        let register = UploadRequest::RegisterPart {
+            path: self.dst_path.to_string_lossy().to_string(),
+            identity: self.identity.clone(),
+        };
+        let Registered::At(_) = handshake(&mut framed, register, self.identity.size).await? else {
+            bail!("server did not register the part");
+        };
+        let compression = negotiate(&mut framed, self.offer).await?;
+
+        let mut file = File::open(&self.local_path).await?;
+        file.seek(SeekFrom::Start(self.range.start)).await?;
+        let mut offset = self.range.start;
+        while offset < self.range.end {
+            self.stop.check()?;
+            let len = (self.range.end - offset).min(self.chunk_size as u64);
+            let mut bytes = BytesMut::with_capacity(len as usize);
+            let mut chunk = (&mut file).take(len);
+            while chunk.read_buf(&mut bytes).await? > 0 {}
+            ensure!(
+                bytes.len() as u64 == len,
+                "local file is shorter than expected"
+            );
+            let data = compress(compression, bytes.freeze()).await?;
+
+            tokio::select! {
+                _ = self.limits.acquire(data.len() as u64) => {}
+                _ = self.stop.stopped() => {}
+            }
+            self.stop.check()?;
+
+            framed
+                .send(UploadRequest::UploadAt { offset, data })
+                .await?;
+            offset += len;
+            let _ = self.sent.send(len);
+        }
+
+        framed.send(UploadRequest::EndPart).await?;
+        let received = match framed.next().await {
+            Some(Ok(UploadResponse::PartReceived(received))) => received,
+            Some(Ok(_)) => bail!("unexpected server msg after a part"),
+            Some(Err(err)) => bail!("failed to decode server part msg: {}", err),
+            None => bail!("server closed connection before the part was received"),
+        };
+        let expect = self.range.end - self.range.start;
+        ensure!(
+            received == expect,
+            "server received {} bytes of part {:?}, expect {}",
+            received,
+            self.range,
+            expect
+        );
+        debug!(range = ?self.range, "part sent");
+        Ok(())
+    }
+}
+
+/// Splits `range` into at most `parts` ranges, which start at a multiple of `align`
+/// from the start of `range`.
+fn split_range(range: Range<u64>, parts: usize, align: u64) -> Vec<Range<u64>> {
+    let len = range.end - range.start;
+    let part_len = len.div_ceil(parts as u64).div_ceil(align) * align;
+    (0..parts as u64)
+        .map(|i| range.start + i * part_len)
+        .take_while(|start| *start < range.end)
+        .map(|start| start..(start + part_len).min(range.end))
+        .collect()
+}
+
+/// Returns the bytes read and the hex encoded SHA-256 of the whole file.
+async fn hash_file(path: &Path, chunk_size: usize) -> Result<(u64, String)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut bytes = BytesMut::with_capacity(chunk_size);
+    let mut read_size = 0;
+    loop {
+        let len = file.read_buf(&mut bytes).await?;
+        if len == 0 {
+            break;
+        }
+        read_size += len as u64;
+        hasher.update(&bytes);
+        bytes.clear();
+    }
+    Ok((read_size, format!("{:x}", hasher.finalize())))
+}
+
+/// How the server answered the registration of an upload.
+enum Registered {
+    /// the upload starts at this offset
+    At(u64),
+    /// the upload goes to another path, as the destination exists
+    Renamed { path: String, offset: u64 },
+    /// the destination exists and is kept
+    Skipped,
+    /// the destination exists and the upload is rejected
+    Conflict,
+}
+
+/// Registers the connection to the server.
+async fn handshake(
+    framed: &mut Framed<ClientStream, ClientCodec>,
+    register: UploadRequest,
+    size: u64,
+) -> Result<Registered> {
+    framed.send(register).await?;
+    let registered = match framed.next().await {
+        Some(Ok(UploadResponse::RegisterResult(offset))) => {
+            Registered::At(offset.context("server rejected upload client handshake")?)
+        }
+        Some(Ok(UploadResponse::Renamed { path, offset })) => Registered::Renamed { path, offset },
+        Some(Ok(UploadResponse::Skipped)) => Registered::Skipped,
+        Some(Ok(UploadResponse::Conflict)) => Registered::Conflict,
+        Some(Ok(_)) => {
+            bail!("unexpected server handshake msg")
+        }
+        Some(Err(err)) => {
+            error!(?err);
+            bail!("failed to decode server handshake msg: {}", err)
+        }
+        None => {
+            bail!("server didn't send handshake result")
+        }
+    };
+    if let Registered::At(offset) | Registered::Renamed { offset, .. } = registered {
+        ensure!(
+            offset <= size,
+            "server holds more bytes than the local file. offset = {}, size = {}",
+            offset,
+            size
+        );
+        debug!(offset, "handshake ok");
+    }
+    Ok(registered)
+}
+
+/// Agrees with the server on the codec of the chunks sent over the connection.
+async fn negotiate(
+    framed: &mut Framed<ClientStream, ClientCodec>,
+    offer: Compression,
+) -> Result<Compression> {
+    // servers which know nothing about compression are not asked
+    if offer == Compression::None {
+        return Ok(Compression::None);
+    }
+    framed
+        .send(UploadRequest::Negotiate {
+            compression: vec![offer],
+        })
+        .await?;
+    let compression = match framed.next().await {
+        Some(Ok(UploadResponse::Negotiated { compression })) => compression,
+        Some(Ok(_)) => bail!("unexpected server msg while negotiating"),
+        Some(Err(err)) => bail!("failed to decode server negotiate msg: {}", err),
+        None => bail!("server closed connection while negotiating"),
+    };
+    ensure!(
+        compression == offer || compression == Compression::None,
+        "server chose a codec which was not offered: {:?}",
+        compression
+    );
+    debug!(?compression, "negotiated");
+    Ok(compression)
+}
+
+/// The codec to offer for `path`, none if its content is already compressed.
+fn compression_for(path: &Path) -> Compression {
+    if is_compressed(path) {
+        Compression::None
+    } else {
+        get_settings().upload.compression
+    }
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|compressed| compressed.eq_ignore_ascii_case(ext))
+    })
+}
+
+/// Compresses a chunk on a blocking thread, as large chunks take a while.
+async fn compress(compression: Compression, chunk: Bytes) -> Result<Bytes> {
+    if compression == Compression::None {
+        return Ok(chunk);
+    }
+    tokio::task::spawn_blocking(move || compression.compress(chunk)).await?
+}
+
+async fn file_identity(path: &Path) -> Result<FileIdentity> {
+    let meta = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("read metadata of {}", path.display()))?;
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(FileIdentity {
+        size: meta.len(),
+        modified,
+    })
+}
+
 #[derive(Serialize, Clone)]
 pub struct UploadEvent {
-    percent: String,
-    is_done: bool,
+    state: UploadState,
+    #[serde(flatten)]
+    progress: Progress,
+}
+
+impl UploadEvent {
+    pub(super) fn queued() -> Self {
+        Self {
+            state: UploadState::Queued,
+            progress: Progress::default(),
+        }
+    }
 }
 
-fn next_load_task_id() -> u32 {
-    static ID: AtomicU32 = AtomicU32::new(0);
-    ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+/// States of a running upload, how it ends is told by
+/// [`TransferEnd`](crate::transfer::TransferEnd).
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum UploadState {
+    Queued,
+    Running,
+    Verifying,
+    Committing,
+    /// the destination exists and is kept
+    Skipped,
 }
 
-fn next_event_key() -> String {
-    format!("upload-progress-{}", next_load_task_id())
+#[cfg(test)]
+mod test {
+    use crate::{
+        file_system::{
+            enqueue_upload,
+            stand_in::{stand_in, transfers, wait_status},
+        },
+        transfer::{TransferKind, TransferStatus},
+    };
+
+    use super::*;
+
+    /// A local file of `size` bytes, named after the test.
+    fn local_file(name: &str, size: usize) -> Result<PathBuf> {
+        let path =
+            std::env::temp_dir().join(format!("zcode-bench-{}-{}.bin", name, std::process::id()));
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    #[tokio::test]
+    async fn t_reconnect() -> Result<()> {
+        let server = stand_in();
+        let size = 1024 * 1024;
+        let local = local_file("reconnect", size)?;
+        let dst = format!(
+            "/reconnect/{}",
+            local.file_name().unwrap().to_string_lossy()
+        );
+        server.drop_at(&dst, 128 * 1024);
+
+        // slow enough for the client to see the connection dropped while sending
+        let transfers = transfers(Some(512 * 1024));
+        let to_dir = UnixPath(PathBuf::from("/reconnect"));
+        let info = enqueue_upload(
+            &transfers,
+            TransferKind::Upload,
+            &local,
+            &to_dir,
+            ConflictPolicy::Fail,
+        )?;
+        let done = wait_status(&transfers, info.id, TransferStatus::Done).await;
+        assert_eq!(done.confirmed_bytes, size as u64);
+
+        // resumed at the offset of the server, the hash covering the bytes sent
+        // before the drop was verified
+        let offsets = server.offsets(&dst);
+        assert_eq!(offsets.len(), 2);
+        assert!(offsets[0] == 0 && offsets[1] >= 128 * 1024 && offsets[1] < size as u64);
+        assert_eq!(server.committed(&dst).unwrap(), std::fs::read(&local)?);
+
+        std::fs::remove_file(&local)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_resume_renamed() -> Result<()> {
+        let server = stand_in();
+        let local = local_file("renamed", 1024 * 1024)?;
+        let dst = format!("/renamed/{}", local.file_name().unwrap().to_string_lossy());
+        let renamed = format!("{} (1)", dst);
+        server.commit(&dst, b"kept");
+
+        // slow enough to pause in the middle of the file
+        let transfers = transfers(Some(512 * 1024));
+        let to_dir = UnixPath(PathBuf::from("/renamed"));
+        let info = enqueue_upload(
+            &transfers,
+            TransferKind::Upload,
+            &local,
+            &to_dir,
+            ConflictPolicy::Rename,
+        )?;
+        while server.received(&renamed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        transfers.pause(info.id)?;
+        let paused = wait_status(&transfers, info.id, TransferStatus::Paused).await;
+        assert_eq!(paused.dst, renamed);
+
+        // resumed at the path chosen by the server, not renamed once more
+        transfers.resume(info.id)?;
+        wait_status(&transfers, info.id, TransferStatus::Done).await;
+        assert_eq!(server.committed(&renamed).unwrap().len(), 1024 * 1024);
+        assert!(server.offsets(&renamed)[1] > 0);
+        assert_eq!(server.committed(&dst).unwrap(), b"kept");
+        assert!(server.offsets(&format!("{} (2)", dst)).is_empty());
+
+        std::fs::remove_file(&local)?;
+        Ok(())
+    }
+
+    #[test]
+    fn t_split_range() {
+        assert_eq!(split_range(10..100, 3, 8), [10..42, 42..74, 74..100]);
+        // never more parts than chunks
+        assert_eq!(split_range(0..10, 4, 8), [0..8, 8..10]);
+        assert_eq!(split_range(0..8, 2, 8), vec![0..8]);
+    }
+
+    #[test]
+    fn t_is_compressed() {
+        assert!(is_compressed(Path::new("/videos/a.MP4")));
+        assert!(is_compressed(Path::new("logs.tar.gz")));
+        assert!(!is_compressed(Path::new("frame.yuv")));
+        assert!(!is_compressed(Path::new("manifest")));
+    }
 }
@@ -0,0 +1,342 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use protocol::upload::ConflictPolicy;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    my_err::DestinationExists,
+    rate_limit::RateLimits,
+    transfer::{Stop, StopSignal, Stopped, TransferEvents},
+};
+
+use super::{
+    progress::{Progress, ProgressMeter},
+    upload::{UploadClient, UploadState},
+    UnixPath,
+};
+
+/// Uploads a local directory and everything in it, one file after another.
+pub struct DirUploadClient {
+    local_path: PathBuf,
+    dst_path: UnixPath,
+    /// relative paths of the directories to create, parents first
+    dirs: Vec<PathBuf>,
+    /// relative paths and sizes of the files to upload
+    files: Vec<(PathBuf, u64)>,
+    events: TransferEvents,
+    stop: StopSignal,
+    limits: RateLimits,
+    on_conflict: ConflictPolicy,
+    /// called with the bytes of the dir the server confirmed to hold
+    on_confirmed: Option<Arc<dyn Fn(u64) + Send + Sync>>,
+    /// bytes of the dir the server held when the transfer last stopped
+    confirmed: u64,
+}
+
+impl DirUploadClient {
+    pub async fn new(
+        src: PathBuf,
+        dst: UnixPath,
+        events: TransferEvents,
+        stop: StopSignal,
+        limits: RateLimits,
+        on_conflict: ConflictPolicy,
+    ) -> Result<Self> {
+        let root = src.clone();
+        let (dirs, files) = tokio::task::spawn_blocking(move || walk_dir(&root)).await??;
+        debug!(dirs = dirs.len(), files = files.len(), "walked local dir");
+
+        Ok(Self {
+            local_path: src,
+            dst_path: dst,
+            dirs,
+            files,
+            events,
+            stop,
+            limits,
+            on_conflict,
+            on_confirmed: None,
+            confirmed: 0,
+        })
+    }
+
+    pub fn on_confirmed(&mut self, f: impl Fn(u64) + Send + Sync + 'static) {
+        self.on_confirmed = Some(Arc::new(f));
+    }
+
+    /// Bytes of the dir the server held when the transfer last stopped, to find
+    /// the file interrupted then.
+    pub fn set_confirmed(&mut self, bytes: u64) {
+        self.confirmed = bytes;
+    }
+
+    pub(super) async fn runn_inner(self) -> Result<()> {
+        if self.stop.reason() == Some(Stop::Cancel) {
+            return self.discard().await;
+        }
+
+        let total_bytes: u64 = self.files.iter().map(|(_, size)| size).sum();
+        let (done_files, done_bytes) = self.on_server();
+        let meter = Arc::new(Mutex::new(ProgressMeter::new(total_bytes, done_bytes)));
+        let mut event = DirUploadEvent {
+            total_files: self.files.len(),
+            done_files,
+            state: UploadState::Running,
+            progress: meter.lock().unwrap().progress(),
+        };
+        self.events.emit(&event);
+
+        self.upload_all(&mut event, &meter).await?;
+        info!(?self.local_path, "send dir done");
+        Ok(())
+    }
+
+    async fn upload_all(
+        &self,
+        event: &mut DirUploadEvent,
+        meter: &Arc<Mutex<ProgressMeter>>,
+    ) -> Result<()> {
+        self.stop.check()?;
+        for dir in &self.dirs {
+            let remote = self.dst_path.join(dir);
+            debug!(?remote, "creating remote dir");
+            create_remote_dir(&remote)
+                .await
+                .with_context(|| format!("create {}", remote.to_string_lossy()))?;
+        }
+
+        let mut base = meter.lock().unwrap().progress().sent_bytes;
+        for (file, size) in self.files.iter().skip(event.done_files) {
+            self.stop.check()?;
+            let local = self.local_path.join(file);
+            let remote = self.dst_path.join(file);
+            // the file interrupted when the transfer last stopped may have been
+            // committed right before, it is not uploaded again
+            let on_conflict = if self.confirmed > 0 && base + size == self.confirmed {
+                ConflictPolicy::Skip
+            } else {
+                self.on_conflict
+            };
+            let mut client = self
+                .file_client(&local, remote, on_conflict)
+                .await
+                .with_context(|| format!("upload {}", local.display()))?;
+            let size = client.size();
+
+            let mut progress = event.clone();
+            let meter_of_file = meter.clone();
+            let events = self.events.clone();
+            if let Some(on_confirmed) = self.on_confirmed.clone() {
+                client.on_confirmed(move |bytes| on_confirmed(base + bytes));
+            }
+            client.on_progress(move |read| {
+                let Some(sent) = meter_of_file.lock().unwrap().update(base + read) else {
+                    return;
+                };
+                progress.progress = sent;
+                events.emit(&progress);
+            });
+
+            client
+                .runn_inner()
+                .await
+                .with_context(|| format!("upload {}", local.display()))?;
+
+            base += size;
+            event.done_files += 1;
+            // a skipped file reports no progress of its own
+            meter.lock().unwrap().update(base);
+            event.progress = meter.lock().unwrap().progress();
+            self.events.emit(&*event);
+        }
+
+        Ok(())
+    }
+
+    /// Files and bytes of the dir wholly on the server when the transfer last
+    /// stopped, which are not registered again.
+    fn on_server(&self) -> (usize, u64) {
+        let mut base = 0;
+        for (i, (_, size)) in self.files.iter().enumerate() {
+            if base + size >= self.confirmed {
+                return (i, base);
+            }
+            base += size;
+        }
+        (self.files.len(), base)
+    }
+
+    async fn file_client(
+        &self,
+        local: &Path,
+        remote: UnixPath,
+        on_conflict: ConflictPolicy,
+    ) -> Result<UploadClient> {
+        UploadClient::new(
+            local.to_path_buf(),
+            remote,
+            // progress of the whole dir is reported under the key of the transfer
+            self.events.muted(),
+            self.stop.clone(),
+            self.limits.clone(),
+            on_conflict,
+        )
+        .await
+    }
+
+    /// Tells the server to drop the partial file of the upload interrupted when
+    /// the transfer stopped. No remote dir is created.
+    async fn discard(&self) -> Result<()> {
+        let mut base = 0;
+        for (file, size) in &self.files {
+            base += size;
+            // the files before are fully on the server
+            if base <= self.confirmed {
+                continue;
+            }
+            let local = self.local_path.join(file);
+            debug!(?local, "discarding partial upload");
+            let remote = self.dst_path.join(file);
+            let client = match self.file_client(&local, remote, self.on_conflict).await {
+                Ok(client) => client,
+                // a skipped file before the interrupted one may hold no confirmed bytes
+                Err(err) if err.is::<DestinationExists>() => continue,
+                Err(err) => return Err(err),
+            };
+            // the client sends the cancel right away, as the transfer is stopped.
+            // only a skipped file, already on the server, returns ok
+            client.runn_inner().await?;
+        }
+        Err(Stopped(Stop::Cancel).into())
+    }
+}
+
+/// Creates a remote dir, which may already exist when uploading into it again.
+async fn create_remote_dir(remote: &UnixPath) -> Result<()> {
+    let path = remote.to_string_lossy();
+    let Err(err) = super::create_dir(Path::new(&*path)).await else {
+        return Ok(());
+    };
+    // the server tells no reason, look whether the dir is there
+    let parent = remote.0.parent().map(|p| UnixPath(p.to_path_buf()));
+    let (Some(parent), Some(name)) = (parent, remote.0.file_name()) else {
+        return Err(err.into());
+    };
+    match super::load_dir_content(PathBuf::from(&*parent.to_string_lossy())).await {
+        Ok(nodes) if nodes.iter().any(|n| n.is_dir() && name == n.name.as_str()) => {
+            debug!(?remote, "remote dir exists");
+            Ok(())
+        }
+        _ => Err(err.into()),
+    }
+}
+
+/// relative paths of dirs, and relative paths and sizes of files
+type DirEntries = (Vec<PathBuf>, Vec<(PathBuf, u64)>);
+
+/// Collects the directories and files under `root`, relative to `root`.
+fn walk_dir(root: &Path) -> Result<DirEntries> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(root)?.to_path_buf();
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            dirs.push(relative);
+        } else if file_type.is_file() {
+            files.push((relative, entry.metadata()?.len()));
+        } else {
+            warn!(path = ?entry.path(), "skip entry which is neither a file nor a dir");
+        }
+    }
+
+    Ok((dirs, files))
+}
+
+#[derive(Serialize, Clone)]
+pub struct DirUploadEvent {
+    total_files: usize,
+    done_files: usize,
+    state: UploadState,
+    #[serde(flatten)]
+    progress: Progress,
+}
+
+impl DirUploadEvent {
+    pub(super) fn queued() -> Self {
+        Self {
+            total_files: 0,
+            done_files: 0,
+            state: UploadState::Queued,
+            progress: Progress::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        file_system::{
+            enqueue_upload,
+            stand_in::{stand_in, transfers, wait_status},
+        },
+        transfer::{TransferKind, TransferStatus},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn t_resume_dir() -> Result<()> {
+        let server = stand_in();
+        let local = std::env::temp_dir().join(format!("zcode-bench-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&local)?;
+        let sizes = [("a", 64 * 1024), ("b", 64 * 1024), ("c", 1024 * 1024)];
+        for (name, size) in sizes {
+            let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            std::fs::write(local.join(name), content)?;
+        }
+        let remote = |name| {
+            let dir = local.file_name().unwrap().to_string_lossy().to_string();
+            format!("/resume/{}/{}", dir, name)
+        };
+
+        // slow enough to pause in the middle of the last file
+        let transfers = transfers(Some(512 * 1024));
+        let to_dir = UnixPath(PathBuf::from("/resume"));
+        let info = enqueue_upload(
+            &transfers,
+            TransferKind::UploadDir,
+            &local,
+            &to_dir,
+            ConflictPolicy::Fail,
+        )?;
+        while server.received(&remote("c")) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        transfers.pause(info.id)?;
+        wait_status(&transfers, info.id, TransferStatus::Paused).await;
+
+        transfers.resume(info.id)?;
+        wait_status(&transfers, info.id, TransferStatus::Done).await;
+        for (name, size) in sizes {
+            let content = server.committed(&remote(name)).unwrap();
+            assert_eq!(content.len(), size, "{}", name);
+        }
+        // the files before the interrupted one are not registered again
+        assert_eq!(server.offsets(&remote("a")).len(), 1);
+        assert!(server.offsets(&remote("c"))[1] > 0);
+
+        std::fs::remove_dir_all(&local)?;
+        Ok(())
+    }
+}
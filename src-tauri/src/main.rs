@@ -1,23 +1,56 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::time::{Duration, Instant};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use serde_json::Value;
 use settings::load_setttings;
-use tauri::{Manager, Runtime};
+use tauri::{AppHandle, FileDropEvent, Manager, Runtime, WindowEvent};
 use tracing::Level;
 
+use crate::auth::login;
+use crate::auth::logout;
 use crate::file_system::create_dir;
 use crate::file_system::delete_file;
+use crate::file_system::download_file;
 use crate::file_system::load_dir_content;
 use crate::file_system::load_dir_tree;
 use crate::file_system::move_to;
+use crate::file_system::restore_transfers;
+use crate::file_system::set_current_dir;
+use crate::file_system::upload_dir;
+use crate::file_system::upload_dropped;
 use crate::file_system::upload_file;
+use crate::file_system::CurrentDir;
+use crate::profile::add_profile;
+use crate::profile::list_profiles;
+use crate::profile::switch_profile;
+use crate::settings::commands::get_settings;
+use crate::settings::commands::update_settings;
+use crate::transfer::cancel_transfer;
+use crate::transfer::get_global_rate_limit;
+use crate::transfer::get_transfer;
+use crate::transfer::list_transfers;
+use crate::transfer::pause_transfer;
+use crate::transfer::resume_transfer;
+use crate::transfer::set_rate_limit;
+use crate::transfer::subscribe_transfer;
+use crate::transfer::EmitFn;
+use crate::transfer::TransferManager;
 
+pub mod auth;
 pub mod client;
+pub mod credentials;
 pub mod file_system;
 pub mod my_err;
+pub mod profile;
+pub mod rate_limit;
 pub mod settings;
+pub mod transfer;
 pub mod utils;
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
@@ -45,21 +78,112 @@ async fn hello_event<R: Runtime>(
     Ok(())
 }
 
+/// how often a reload of the profiles held back by running transfers is tried
+const PROFILE_RELOAD_RETRY: Duration = Duration::from_secs(2);
+
+/// Applies changed settings to what does not read them on every use, and
+/// tells the frontend.
+fn on_settings_changed<R: Runtime>(app: AppHandle<R>, transfers: TransferManager) {
+    let mut changes = settings::subscribe();
+    tauri::async_runtime::spawn(async move {
+        // settings whose profiles wait for the transfers to finish
+        let mut deferred = None;
+        let mut retry = tokio::time::interval(PROFILE_RELOAD_RETRY);
+        loop {
+            let (settings, retried) = tokio::select! {
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let settings = changes.borrow_and_update().clone();
+                    transfers.set_max_concurrency(settings.transfer.max_concurrency);
+                    log_if_err!(transfers.set_rate_limit(None, settings.upload.max_rate));
+                    transfers.set_default_rate_limit(settings.upload.max_rate_per_transfer);
+                    (settings, false)
+                }
+                _ = retry.tick(), if deferred.is_some() => match deferred.take() {
+                    Some(settings) => (settings, true),
+                    None => continue,
+                },
+            };
+            deferred = None;
+            if !profile::reload(&settings, &transfers).await {
+                if !retried {
+                    tracing::info!("the active profile changes once the transfers are stopped");
+                    log_if_err!(app.emit_all("settings-changed", &*settings));
+                }
+                deferred = Some(settings);
+                continue;
+            }
+            log_if_err!(app.emit_all("settings-changed", &*settings));
+        }
+    });
+}
+
+async fn init_credentials(kind: credentials::StoreKind, file: PathBuf) -> anyhow::Result<()> {
+    tauri::async_runtime::spawn_blocking(move || credentials::init(kind, file)).await?
+}
+
 fn main() -> anyhow::Result<()> {
-    load_setttings()?;
+    let settings = load_setttings()?;
     tracing_subscriber::fmt()
         .with_max_level(Level::DEBUG)
         .init();
 
     tauri::Builder::default()
-        .setup(|app| {
+        .setup(move |app| {
+            let window = app.get_window("main").unwrap();
             #[cfg(debug_assertions)] // only include this code on debug builds
             {
-                let window = app.get_window("main").unwrap();
                 window.open_devtools();
                 // window.close_devtools();
             }
 
+            let app_handle = app.handle();
+            window.on_window_event(move |event| {
+                if let WindowEvent::FileDrop(FileDropEvent::Dropped(paths)) = event {
+                    upload_dropped(&app_handle, paths);
+                }
+            });
+
+            let app_data_dir = app.path_resolver().app_data_dir();
+            let state_file = app_data_dir.as_ref().map(|dir| dir.join("transfers.json"));
+            let emit: EmitFn = Arc::new(move |event_key: &str, event: &Value| {
+                log_if_err!(window.emit(event_key, event));
+            });
+            let profiles_file = app_data_dir.as_ref().map(|dir| dir.join("profiles.json"));
+            profile::init(&settings, profiles_file);
+            let transfers =
+                TransferManager::new(settings.transfer.max_concurrency, state_file, emit)
+                    .with_rate_limits(
+                        settings.upload.max_rate,
+                        settings.upload.max_rate_per_transfer,
+                    )
+                    .with_active_profile(Arc::new(|| profile::active().name.clone()));
+            restore_transfers(&transfers);
+            on_settings_changed(app.handle(), transfers.clone());
+            settings::watch_files();
+            app.manage(transfers);
+            app.manage(CurrentDir::default());
+
+            let credentials_file = settings
+                .credentials
+                .file
+                .clone()
+                .or_else(|| app_data_dir.map(|dir| dir.join("credentials.enc")));
+            let kind = settings.credentials.store;
+            // the keyring probe and the key derivation are slow, keep them off the setup hook
+            tauri::async_runtime::spawn(async move {
+                if let Some(file) = credentials_file {
+                    if let Err(err) = init_credentials(kind, file).await {
+                        tracing::warn!(?err, "the session will not be kept between runs");
+                    }
+                }
+                // without a credential store nothing is restored, the requests
+                // waiting for the session are let through
+                auth::restore_session().await;
+            });
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -67,10 +191,28 @@ fn main() -> anyhow::Result<()> {
             hello_event,
             load_dir_tree,
             upload_file,
+            upload_dir,
+            download_file,
             load_dir_content,
             delete_file,
             create_dir,
-            move_to
+            move_to,
+            list_transfers,
+            get_transfer,
+            pause_transfer,
+            resume_transfer,
+            cancel_transfer,
+            subscribe_transfer,
+            set_rate_limit,
+            get_global_rate_limit,
+            set_current_dir,
+            login,
+            logout,
+            list_profiles,
+            add_profile,
+            switch_profile,
+            get_settings,
+            update_settings
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
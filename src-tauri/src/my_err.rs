@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 use serde::{ser::SerializeStruct, Serialize};
 
 pub type MyResult<T, E = MyErr> = std::result::Result<T, E>;
@@ -23,8 +25,41 @@ impl Serialize for MyErr {
     where
         S: serde::Serializer,
     {
-        let mut s = serializer.serialize_struct("Error", 1)?;
+        let mut s = serializer.serialize_struct("Error", 3)?;
         s.serialize_field("msg", &format!("{:?}", self.err))?;
+        // lets the frontend handle errors it knows, instead of only showing them
+        if let Some(exists) = self.err.downcast_ref::<DestinationExists>() {
+            s.serialize_field("code", "destination_exists")?;
+            s.serialize_field("path", &exists.path)?;
+        } else if self.err.is::<Unauthorized>() {
+            s.serialize_field("code", "unauthorized")?;
+        }
         s.end()
     }
 }
+
+/// The destination of an upload already exists, and the upload is not allowed to replace it.
+#[derive(Debug)]
+pub struct DestinationExists {
+    pub path: String,
+}
+
+impl Display for DestinationExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination already exists: {}", self.path)
+    }
+}
+
+impl std::error::Error for DestinationExists {}
+
+/// The server rejected the request, and logging in again is up to the user.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not logged in, or the session expired")
+    }
+}
+
+impl std::error::Error for Unauthorized {}
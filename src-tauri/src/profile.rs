@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    auth, client, log_if_err,
+    my_err::MyResult,
+    settings::{ProfileConfig, RemoteServerConfig, Settings},
+    transfer::TransferManager,
+    utils::write_atomic,
+};
+
+/// name of the profile of `remote_server`
+pub const DEFAULT_PROFILE: &str = "default";
+
+static PROFILES: OnceLock<Profiles> = OnceLock::new();
+
+/// A server the app can talk to, and the session used with it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: ProfileConfig,
+}
+
+impl Profile {
+    pub fn display_name(&self) -> &str {
+        self.config.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// key of the session of the profile in the credential store
+    pub fn credentials(&self) -> &str {
+        self.config.credentials.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn server(&self) -> &RemoteServerConfig {
+        &self.config.server
+    }
+}
+
+/// What the frontend is told about a profile.
+#[derive(Serialize)]
+pub struct ProfileInfo {
+    name: String,
+    display_name: String,
+    http: String,
+    tcp: String,
+    active: bool,
+    /// profiles of the settings can not be replaced from the app
+    configured: bool,
+}
+
+/// The profiles of the settings, the ones added from the app, and which one
+/// is used.
+pub struct Profiles {
+    state: RwLock<ProfilesState>,
+    /// where the added profiles and the active one are kept between runs
+    state_file: Option<PathBuf>,
+}
+
+struct ProfilesState {
+    active: Arc<Profile>,
+    configured: BTreeMap<String, Arc<Profile>>,
+    added: BTreeMap<String, Arc<Profile>>,
+}
+
+impl ProfilesState {
+    fn get(&self, name: &str) -> Option<Arc<Profile>> {
+        self.configured
+            .get(name)
+            .or_else(|| self.added.get(name))
+            .cloned()
+    }
+}
+
+/// What reloading the profiles did to the active one.
+#[derive(Debug, PartialEq)]
+pub enum Reload {
+    /// the active profile is the same in the new settings
+    Updated,
+    /// the active profile is changed by the new settings
+    Changed,
+    /// the active profile is no longer in the settings, the default one is used
+    Replaced,
+    /// nothing is taken, the server of the active profile would change
+    Deferred,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedProfiles {
+    active: Option<String>,
+    added: Vec<Profile>,
+}
+
+impl Profiles {
+    pub fn new(settings: &Settings, state_file: Option<PathBuf>) -> Self {
+        let configured = configured(settings);
+        let saved = match &state_file {
+            Some(path) if path.exists() => read_state(path).unwrap_or_else(|err| {
+                error!(?err, ?path, "failed to load saved profiles");
+                SavedProfiles::default()
+            }),
+            _ => SavedProfiles::default(),
+        };
+        let added: BTreeMap<_, _> = saved
+            .added
+            .into_iter()
+            .filter(|profile| !configured.contains_key(&profile.name))
+            .map(|profile| (profile.name.clone(), Arc::new(profile)))
+            .collect();
+        let mut state = ProfilesState {
+            active: configured[DEFAULT_PROFILE].clone(),
+            configured,
+            added,
+        };
+        if let Some(active) = saved.active.and_then(|name| state.get(&name)) {
+            state.active = active;
+        }
+        info!(active = state.active.name, "profile");
+
+        Self {
+            state: RwLock::new(state),
+            state_file,
+        }
+    }
+
+    /// Takes the profiles of changed settings. With `keep_server`, nothing is
+    /// taken if the active profile would talk to another server.
+    pub fn reload(&self, settings: &Settings, keep_server: bool) -> Reload {
+        let mut state = self.state.write().unwrap();
+        let configured = configured(settings);
+        let added = state
+            .added
+            .iter()
+            .filter(|(name, _)| !configured.contains_key(*name))
+            .map(|(name, profile)| (name.clone(), profile.clone()))
+            .collect();
+        let mut next = ProfilesState {
+            active: state.active.clone(),
+            configured,
+            added,
+        };
+        let (active, reload) = match next.get(&state.active.name) {
+            Some(active) if active.config == state.active.config => (active, Reload::Updated),
+            Some(active) => (active, Reload::Changed),
+            None => (next.configured[DEFAULT_PROFILE].clone(), Reload::Replaced),
+        };
+        if keep_server && active.config != state.active.config {
+            return Reload::Deferred;
+        }
+        next.active = active;
+        *state = next;
+        self.save(&state);
+        reload
+    }
+
+    pub fn active(&self) -> Arc<Profile> {
+        self.state.read().unwrap().active.clone()
+    }
+
+    pub fn list(&self) -> Vec<ProfileInfo> {
+        let state = self.state.read().unwrap();
+        let configured = state.configured.values().map(|p| (p, true));
+        let added = state.added.values().map(|p| (p, false));
+        configured
+            .chain(added)
+            .map(|(profile, configured)| ProfileInfo {
+                name: profile.name.clone(),
+                display_name: profile.display_name().to_string(),
+                http: profile.server().http.clone(),
+                tcp: profile.server().tcp.clone(),
+                active: profile.name == state.active.name,
+                configured,
+            })
+            .collect()
+    }
+
+    /// Adds a profile, or replaces the one added before under the same name,
+    /// unless it is active.
+    pub fn add(&self, profile: Profile) -> Result<()> {
+        ensure!(!profile.name.is_empty(), "the name of a profile is empty");
+        profile
+            .server()
+            .validate()
+            .with_context(|| format!("profile {}", profile.name))?;
+
+        let mut state = self.state.write().unwrap();
+        ensure!(
+            !state.configured.contains_key(&profile.name),
+            "profile {} is in the settings and can not be replaced",
+            profile.name
+        );
+        // the session and the transfers of the active profile belong to its server
+        ensure!(
+            state.active.name != profile.name,
+            "profile {} is active and can not be replaced, switch to another one first",
+            profile.name
+        );
+        state.added.insert(profile.name.clone(), Arc::new(profile));
+        self.save(&state);
+        Ok(())
+    }
+
+    /// Makes `name` the active profile, and returns it.
+    pub fn switch(&self, name: &str) -> Result<Arc<Profile>> {
+        let mut state = self.state.write().unwrap();
+        let Some(profile) = state.get(name) else {
+            bail!("profile not found: {}", name);
+        };
+        state.active = profile.clone();
+        self.save(&state);
+        Ok(profile)
+    }
+
+    fn save(&self, state: &ProfilesState) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let saved = SavedProfiles {
+            active: Some(state.active.name.clone()),
+            added: state.added.values().map(|p| (**p).clone()).collect(),
+        };
+        log_if_err!(write_state(path, &saved));
+    }
+}
+
+/// `remote_server` as the default profile, and the profiles of the settings
+fn configured(settings: &Settings) -> BTreeMap<String, Arc<Profile>> {
+    let default = Profile {
+        name: DEFAULT_PROFILE.to_string(),
+        config: ProfileConfig {
+            display_name: None,
+            credentials: None,
+            server: settings.remote_server.clone(),
+        },
+    };
+    settings
+        .profiles
+        .iter()
+        .map(|(name, config)| Profile {
+            name: name.clone(),
+            config: config.clone(),
+        })
+        .chain([default])
+        .map(|profile| (profile.name.clone(), Arc::new(profile)))
+        .collect()
+}
+
+fn read_state(path: &Path) -> Result<SavedProfiles> {
+    let content = std::fs::read(path).context("read profiles file")?;
+    let saved = serde_json::from_slice(&content).context("parse profiles file")?;
+    Ok(saved)
+}
+
+fn write_state(path: &Path, saved: &SavedProfiles) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(saved)?)
+}
+
+/// Sets up the profiles, before anything talks to the server.
+pub fn init(settings: &Settings, state_file: Option<PathBuf>) {
+    if PROFILES.set(Profiles::new(settings, state_file)).is_err() {
+        panic!("profiles initialized twice");
+    }
+}
+
+/// The profile the http api and the tcp channel talk to.
+pub fn active() -> Arc<Profile> {
+    profiles().active()
+}
+
+fn profiles() -> &'static Profiles {
+    PROFILES.get().expect("profiles not initialized")
+}
+
+/// Takes the profiles of changed settings, and talks to the server of the
+/// active profile from now on. The server is not changed under running
+/// transfers, which would reconnect to the other one. Returns false then,
+/// and should be called again once they are stopped.
+pub async fn reload(settings: &Settings, transfers: &TransferManager) -> bool {
+    match profiles().reload(settings, transfers.has_running()) {
+        Reload::Updated => client::reset_http_client(),
+        Reload::Changed => {
+            client::reset_http_client();
+            auth::switch_session().await;
+        }
+        Reload::Replaced => {
+            client::reset_http_client();
+            warn!("active profile removed from the settings, switched to the default one");
+            auth::switch_session().await;
+        }
+        Reload::Deferred => return false,
+    }
+    true
+}
+
+#[tauri::command]
+pub fn list_profiles() -> Vec<ProfileInfo> {
+    profiles().list()
+}
+
+#[instrument(skip_all, fields(name = profile.name))]
+#[tauri::command]
+pub fn add_profile(profile: Profile) -> MyResult<()> {
+    profiles().add(profile)?;
+    info!("profile added");
+    Ok(())
+}
+
+/// Points the app at another server. The session of that server is restored,
+/// so the frontend should load everything again.
+#[instrument(skip(transfers))]
+#[tauri::command]
+pub async fn switch_profile(transfers: State<'_, TransferManager>, name: String) -> MyResult<()> {
+    // running transfers connect again to the active profile when they retry
+    if transfers.has_running() {
+        return Err(anyhow!("can not switch profile while transfers are running").into());
+    }
+
+    let profile = profiles().switch(&name)?;
+    client::reset_http_client();
+    auth::switch_session().await;
+    info!(display_name = profile.display_name(), "profile switched");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> Settings {
+        let toml = r#"
+            [remote_server]
+            http = "localhost:36743"
+            tcp = "localhost:48371"
+
+            [profiles.lab]
+            display_name = "Lab"
+            http = "https://lab.example.com"
+            tcp = "lab.example.com:48371"
+        "#;
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn profile(name: &str, http: &str) -> Profile {
+        Profile {
+            name: name.to_string(),
+            config: ProfileConfig {
+                display_name: None,
+                credentials: Some("shared".to_string()),
+                server: RemoteServerConfig {
+                    http: http.to_string(),
+                    tcp: "localhost:1".to_string(),
+                    tls: None,
+                    https: Default::default(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn t_profiles() {
+        let path = std::env::temp_dir().join(format!("profiles-{}.json", std::process::id()));
+        let profiles = Profiles::new(&settings(), Some(path.clone()));
+        assert_eq!(profiles.active().name, DEFAULT_PROFILE);
+        assert_eq!(profiles.list().len(), 2);
+
+        assert!(profiles.add(profile("lab", "localhost:2")).is_err());
+        assert!(profiles.add(profile("bad", "ftp://localhost")).is_err());
+        profiles.add(profile("home", "localhost:2")).unwrap();
+        assert!(profiles.switch("nope").is_err());
+        let home = profiles.switch("home").unwrap();
+        assert_eq!(home.display_name(), "home");
+        assert_eq!(home.credentials(), "shared");
+
+        // the added profiles and the active one are kept between runs
+        let profiles = Profiles::new(&settings(), Some(path.clone()));
+        assert_eq!(profiles.active().name, "home");
+        let lab = profiles
+            .list()
+            .into_iter()
+            .find(|p| p.name == "lab")
+            .unwrap();
+        assert_eq!(lab.display_name, "Lab");
+        assert!(lab.configured);
+        assert!(profiles.add(profile("home", "localhost:3")).is_err());
+
+        // a profile removed from the settings is no longer used
+        profiles.switch("lab").unwrap();
+        let mut changed = settings();
+        changed.profiles.clear();
+        // not under running transfers
+        assert_eq!(profiles.reload(&changed, true), Reload::Deferred);
+        assert_eq!(profiles.active().name, "lab");
+        assert_eq!(profiles.reload(&settings(), true), Reload::Updated);
+        let mut moved = settings();
+        moved.profiles.get_mut("lab").unwrap().server.http = "https://lab2.example.com".into();
+        assert_eq!(profiles.reload(&moved, false), Reload::Changed);
+        assert_eq!(profiles.reload(&changed, false), Reload::Replaced);
+        assert_eq!(profiles.active().name, DEFAULT_PROFILE);
+        assert_eq!(profiles.list().len(), 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
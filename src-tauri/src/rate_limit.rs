@@ -0,0 +1,108 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// bytes sent at full speed before the limit applies, as a duration at the limit rate
+const BURST: Duration = Duration::from_secs(1);
+
+/// A token bucket limiting how many bytes per second go through it.
+///
+/// Waiting is done before sending, so a chunk larger than the bucket only delays
+/// the chunks after it.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    state: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// bytes per second, unlimited if none
+    rate: Option<u64>,
+    /// when the bytes taken so far are all paid for at `rate`
+    paid_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let this = Self::default();
+        this.set_rate(rate);
+        this
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Changes the limit, effective for the next bytes sent.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        state.paid_until = None;
+    }
+
+    /// Waits until `bytes` may be sent.
+    pub async fn acquire(&self, bytes: u64) {
+        let delay = self.reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returns how long to wait before sending them.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let Some(rate) = state.rate else {
+            return Duration::ZERO;
+        };
+        let nanos = bytes as u128 * 1_000_000_000 / rate as u128;
+        let cost = Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX));
+        let start = match state.paid_until {
+            Some(paid_until) if paid_until > now => paid_until,
+            _ => now,
+        };
+        let paid_until = start + cost;
+        state.paid_until = Some(paid_until);
+        paid_until.saturating_duration_since(now + BURST)
+    }
+}
+
+/// Every limit an upload goes through.
+#[derive(Clone)]
+pub struct RateLimits {
+    /// shared by all uploads
+    pub global: RateLimiter,
+    /// of this transfer only
+    pub transfer: RateLimiter,
+}
+
+impl RateLimits {
+    pub async fn acquire(&self, bytes: u64) {
+        self.global.acquire(bytes).await;
+        self.transfer.acquire(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_token_bucket() {
+        let limiter = RateLimiter::new(Some(1000));
+        let now = Instant::now();
+
+        // a burst of one second is let through
+        assert_eq!(limiter.reserve(600, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(400, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, now), Duration::from_millis(500));
+        // tokens come back with time
+        let later = now + Duration::from_secs(3);
+        assert_eq!(limiter.reserve(1000, later), Duration::ZERO);
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.reserve(u64::MAX, later), Duration::ZERO);
+        limiter.set_rate(Some(100));
+        assert_eq!(limiter.reserve(200, later), Duration::from_secs(1));
+    }
+}
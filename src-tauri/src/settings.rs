@@ -1,44 +1,271 @@
-use std::sync::OnceLock;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use config::Config;
-use serde::Deserialize;
+use protocol::compression::Compression;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
 
-#[derive(Deserialize)]
+use crate::{credentials::StoreKind, profile, utils::write_atomic};
+
+pub mod commands;
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Settings {
+    /// the server of the `default` profile
     pub remote_server: RemoteServerConfig,
+    /// more servers the app can switch to, by name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+}
+
+impl Settings {
+    fn validate(&self) -> Result<()> {
+        self.remote_server.validate()?;
+        for (name, profile) in &self.profiles {
+            ensure!(
+                name != profile::DEFAULT_PROFILE,
+                "profiles.{} is the name of the profile of remote_server",
+                name
+            );
+            profile
+                .server
+                .validate()
+                .with_context(|| format!("profiles.{}", name))?;
+        }
+        self.upload.validate()?;
+        self.transfer.validate()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// bytes read from the local file and sent to the server in one frame
+    pub chunk_size: usize,
+    /// bytes per second sent by all uploads together, unlimited if not set
+    pub max_rate: Option<u64>,
+    /// bytes per second sent by one upload, unlimited if not set
+    pub max_rate_per_transfer: Option<u64>,
+    /// connections sending parts of a large file at the same time
+    pub parallel_streams: usize,
+    /// files with less bytes left to send are sent over a single connection
+    pub parallel_min_size: u64,
+    /// codec offered to the server for the chunks of an upload. files already
+    /// compressed, like videos or archives, are always sent as is
+    pub compression: Compression,
+}
+
+impl UploadConfig {
+    const MIN_CHUNK_SIZE: usize = 4 * 1024;
+    const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+    const MAX_PARALLEL_STREAMS: usize = 16;
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            (Self::MIN_CHUNK_SIZE..=Self::MAX_CHUNK_SIZE).contains(&self.chunk_size),
+            "upload.chunk_size must be between {} and {} bytes, got {}",
+            Self::MIN_CHUNK_SIZE,
+            Self::MAX_CHUNK_SIZE,
+            self.chunk_size
+        );
+        ensure!(
+            self.max_rate != Some(0) && self.max_rate_per_transfer != Some(0),
+            "upload rate limits must be greater than 0, leave them out for no limit"
+        );
+        ensure!(
+            (1..=Self::MAX_PARALLEL_STREAMS).contains(&self.parallel_streams),
+            "upload.parallel_streams must be between 1 and {}, got {}",
+            Self::MAX_PARALLEL_STREAMS,
+            self.parallel_streams
+        );
+        Ok(())
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024 * 1024,
+            max_rate: None,
+            max_rate_per_transfer: None,
+            parallel_streams: 1,
+            parallel_min_size: 64 * 1024 * 1024,
+            compression: Compression::None,
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// transfers running at the same time, the others wait in queue
+    pub max_concurrency: usize,
+    /// resume transfers interrupted by closing the app, instead of leaving them paused
+    pub resume_on_startup: bool,
+}
+
+/// Where the session is kept between runs of the app.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct CredentialsConfig {
+    pub store: StoreKind,
+    /// encrypted file used without a keyring, `credentials.enc` in the app data dir if not set
+    pub file: Option<PathBuf>,
+}
+
+impl TransferConfig {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.max_concurrency > 0,
+            "transfer.max_concurrency must be greater than 0"
+        );
+        Ok(())
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 3,
+            resume_on_startup: false,
+        }
+    }
+}
+
+/// A named server, on top of `remote_server`.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ProfileConfig {
+    /// shown in the app, the name of the profile if not set
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// key of the session of the profile in the credential store, the name of
+    /// the profile if not set. profiles with the same key share their login
+    #[serde(default)]
+    pub credentials: Option<String>,
+    #[serde(flatten)]
+    pub server: RemoteServerConfig,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct RemoteServerConfig {
+    /// base url of the http api, with an optional path prefix.
+    /// a bare `host:port` is plain http
     pub http: String,
     pub tcp: String,
+    /// encrypts the tcp channel, plain tcp if not set
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub https: HttpsConfig,
+}
+
+/// TLS of the http api, used when `http` is an https url.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct HttpsConfig {
+    /// PEM bundle of the CAs trusted to sign the server certificate,
+    /// the webpki roots if not set
+    pub ca_file: Option<PathBuf>,
+    /// hex SHA-256 of the DER certificates accepted for the server. if set, the
+    /// server certificate must be one of them, on top of being trusted
+    pub pinned_certs: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM bundle of the CAs trusted to sign the server certificate,
+    /// the webpki roots if not set
+    pub ca_file: Option<PathBuf>,
+    /// name checked against the server certificate, the host of `tcp` if not set
+    pub server_name: Option<String>,
+    /// PEM certificate chain sent if the server asks for a client certificate
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.client_cert.is_some() == self.client_key.is_some(),
+            "remote_server.tls.client_cert and client_key must be set together"
+        );
+        Ok(())
+    }
 }
 
 impl RemoteServerConfig {
+    pub fn validate(&self) -> Result<()> {
+        let base_url = self.base_url();
+        let url = Url::parse(&base_url)
+            .with_context(|| format!("remote_server.http is not a valid url: {}", base_url))?;
+        ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "remote_server.http must be an http or https url, got {}",
+            base_url
+        );
+        ensure!(
+            url.query().is_none() && url.fragment().is_none(),
+            "remote_server.http must not have a query or fragment, got {}",
+            base_url
+        );
+        match &self.tls {
+            Some(tls) => tls.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// `http` as a url without trailing slash, plain http if it has no scheme
+    fn base_url(&self) -> String {
+        let base = self.http.trim_end_matches('/');
+        if base.contains("://") {
+            base.to_string()
+        } else {
+            format!("http://{}", base)
+        }
+    }
+
+    /// url of `path` on the server of the active profile
+    fn endpoint(path: &str) -> String {
+        format!("{}/{}", profile::active().server().base_url(), path)
+    }
+
+    pub fn url_login() -> String {
+        Self::endpoint("api/auth/login")
+    }
+
     pub fn api_load_structure() -> String {
-        let host = &get_settings().remote_server.http;
-        format!("http://{}/api/fs/load_structure", host)
+        Self::endpoint("api/fs/load_structure")
     }
 
     pub fn api_load_dir_content() -> String {
-        let host = &get_settings().remote_server.http;
-        format!("http://{}/api/fs/load_dir_content", host)
+        Self::endpoint("api/fs/load_dir_content")
     }
 
     pub fn url_delete_file() -> String {
-        let host = &get_settings().remote_server.http;
-        format!("http://{}/api/fs/delete", host)
+        Self::endpoint("api/fs/delete")
     }
 
     pub fn url_create_dir() -> String {
-        let host = &get_settings().remote_server.http;
-        format!("http://{}/api/fs/create_dir", host)
+        Self::endpoint("api/fs/create_dir")
     }
 
     pub fn url_move() -> String {
-        let host = &get_settings().remote_server.http;
-        format!("http://{}/api/fs/move", host)
+        Self::endpoint("api/fs/move")
     }
 }
 
@@ -52,30 +279,458 @@ mod run_mode {
     pub static RUN_MODE_BETA: &str = "beta";
 }
 
-pub fn load_setttings() -> Result<&'static Settings> {
+/// name of the dir of the app in the config dir of the user
+const APP_DIR: &str = "zcode-bench";
+/// defaults bundled in the app, so it starts wherever it is launched from
+const BUNDLED_DEFAULTS: &str = include_str!("../configs/default.toml");
+/// prefix of the env vars overriding settings, e.g. `AV1_VIDEO_UPLOAD__MAX_RATE`
+/// for `upload.max_rate`
+const ENV_PREFIX: &str = "AV1_VIDEO";
+/// how often the config files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn run_mode() -> String {
     #[cfg(test)]
     let run_mode = String::from("test");
     #[cfg(not(test))]
     let run_mode =
         std::env::var(run_mode::RUN_MODE_ENV_KEY).unwrap_or_else(|_| "development".into());
+    run_mode
+}
+
+/// Where the settings are read from, on top of the bundled defaults.
+struct ConfigSources {
+    /// later files override earlier ones, the env vars override them all
+    files: Vec<ConfigFile>,
+    /// where the settings changed from the app are saved
+    user_file: Option<PathBuf>,
+}
 
-    println!("server running in {} mode", run_mode);
+#[derive(Clone)]
+struct ConfigFile {
+    path: PathBuf,
+    /// only the file given with `--config` has to exist
+    required: bool,
+}
 
-    let settings = Config::builder()
-        .add_source(config::File::with_name("configs/default.toml"))
-        .add_source(config::File::with_name(&format!(
+impl ConfigSources {
+    /// Looks for the settings in, by priority:
+    /// - the file given with `--config`
+    /// - `user.toml` in the config dir, the settings changed from the app
+    /// - `config.toml` in the config dir, e.g. `~/.config/zcode-bench` on linux
+    /// - `configs/<run mode>.toml` in the working dir, for development
+    fn new(config_dir: Option<PathBuf>, explicit: Option<PathBuf>) -> Self {
+        let optional = |path: PathBuf| ConfigFile {
+            path,
+            required: false,
+        };
+        let mut files = vec![optional(PathBuf::from(format!(
             "configs/{}.toml",
-            run_mode
-        )))
-        .add_source(config::Environment::with_prefix("AV1_VIDEO"))
+            run_mode()
+        )))];
+        let user_file = config_dir.map(|dir| {
+            files.push(optional(dir.join("config.toml")));
+            dir.join("user.toml")
+        });
+        files.extend(user_file.clone().map(optional));
+        files.extend(explicit.map(|path| ConfigFile {
+            path,
+            required: true,
+        }));
+        Self { files, user_file }
+    }
+
+    fn from_env() -> Self {
+        let config_dir = dirs_next::config_dir().map(|dir| dir.join(APP_DIR));
+        Self::new(config_dir, config_arg(std::env::args().skip(1)))
+    }
+
+    /// Reads and validates the settings, without applying them.
+    fn read(&self) -> Result<Settings> {
+        self.read_from(&self.files)
+    }
+
+    /// The settings without the user config, what the changes from the app
+    /// are saved against.
+    fn read_without_user(&self) -> Result<Settings> {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .filter(|file| Some(&file.path) != self.user_file.as_ref())
+            .cloned()
+            .collect();
+        self.read_from(&files)
+    }
+
+    fn read_from(&self, files: &[ConfigFile]) -> Result<Settings> {
+        let read = || -> Result<Settings> {
+            let mut builder = Config::builder().add_source(config::File::from_str(
+                BUNDLED_DEFAULTS,
+                config::FileFormat::Toml,
+            ));
+            for file in files {
+                builder = builder
+                    .add_source(config::File::from(file.path.as_path()).required(file.required));
+            }
+            let settings: Settings = builder
+                .add_source(env_source())
+                .build()?
+                .try_deserialize()?;
+            settings.validate()?;
+            Ok(settings)
+        };
+        read().with_context(|| self.tried())
+    }
+
+    /// The keys set by `--config` and the env vars, which override the
+    /// settings saved from the app.
+    fn pinned(&self) -> Result<toml::Table> {
+        let mut builder = Config::builder();
+        for file in self.files.iter().filter(|file| file.required) {
+            builder = builder.add_source(config::File::from(file.path.as_path()));
+        }
+        let pinned = builder
+            .add_source(env_source())
+            .build()?
+            .try_deserialize()?;
+        Ok(pinned)
+    }
+
+    /// the files looked for, to tell where a wrong setting may come from
+    fn tried(&self) -> String {
+        let mut tried = String::from("settings read from the bundled defaults, then");
+        for file in &self.files {
+            let found = if file.path.is_file() {
+                "found"
+            } else {
+                "not found"
+            };
+            tried += &format!("\n  {} ({})", file.path.display(), found);
+        }
+        tried + &format!("\n  env vars {}_*", ENV_PREFIX)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files
+            .iter()
+            .map(|file| {
+                std::fs::metadata(&file.path)
+                    .and_then(|m| m.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+fn env_source() -> config::Environment {
+    config::Environment::with_prefix(ENV_PREFIX)
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+}
+
+/// path given with `--config <path>` or `--config=<path>`
+fn config_arg(mut args: impl Iterator<Item = String>) -> Option<PathBuf> {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+static SOURCES: OnceLock<ConfigSources> = OnceLock::new();
+
+fn sources() -> &'static ConfigSources {
+    SOURCES.get_or_init(ConfigSources::from_env)
+}
+
+pub fn load_setttings() -> Result<Arc<Settings>> {
+    println!("server running in {} mode", run_mode());
+
+    let settings = Arc::new(sources().read()?);
+    if SETTINGS.set(watch::channel(settings.clone()).0).is_err() {
+        bail!("settings loaded twice");
+    }
+    Ok(settings)
+}
+
+/// The current settings, swapped as a whole when they change. Read them again
+/// on every use, rather than keeping them, to pick up changes.
+static SETTINGS: OnceLock<watch::Sender<Arc<Settings>>> = OnceLock::new();
+
+/// Sets the bundled defaults with the toml `overrides` on top as the current
+/// settings, for the tests which talk to a server. Only the first call of a
+/// test run sets them.
+#[cfg(test)]
+pub fn load_for_test(overrides: &str) -> Result<Arc<Settings>> {
+    let settings: Settings = Config::builder()
+        .add_source(config::File::from_str(
+            BUNDLED_DEFAULTS,
+            config::FileFormat::Toml,
+        ))
+        .add_source(config::File::from_str(overrides, config::FileFormat::Toml))
         .build()?
         .try_deserialize()?;
+    settings.validate()?;
+    let current = SETTINGS.get_or_init(|| watch::channel(Arc::new(settings)).0);
+    Ok(current.borrow().clone())
+}
+
+pub fn get_settings() -> Arc<Settings> {
+    SETTINGS.get().unwrap().borrow().clone()
+}
+
+/// Notified every time the settings change.
+pub fn subscribe() -> watch::Receiver<Arc<Settings>> {
+    SETTINGS.get().unwrap().subscribe()
+}
+
+/// Validates `settings`, saves them to the user config, and applies them.
+///
+/// Only the keys which differ from the other config files are saved, so the
+/// ones not changed from the app still follow those files. Keys set by
+/// `--config` or the env vars can not be changed, they would override the
+/// saved ones.
+pub fn update_settings(settings: Settings) -> Result<()> {
+    settings.validate()?;
+    let sources = sources();
+    let Some(path) = &sources.user_file else {
+        bail!("no config dir to save the settings in");
+    };
+    let pinned = sources.pinned().context("read --config and env vars")?;
+    let current = to_table(&get_settings())?;
+    let changed = pinned_change(&pinned, Some(&current), Some(&to_table(&settings)?), "");
+    if let Some(key) = changed {
+        bail!(
+            "{} is set by --config or an env var, and can not be changed from the app",
+            key
+        );
+    }
+    let base = sources.read_without_user()?;
+    let changed = changed_keys(&to_table(&base)?, &to_table(&settings)?, "")?;
+    let content = toml::to_string_pretty(&changed).context("serialize settings")?;
+    // the watcher never reads a truncated file
+    write_atomic(path, content.as_bytes()).context("write user config")?;
+    apply(settings);
+    Ok(())
+}
+
+fn to_table(settings: &Settings) -> Result<toml::Table> {
+    match toml::Value::try_from(settings).context("serialize settings")? {
+        toml::Value::Table(table) => Ok(table),
+        _ => bail!("settings are not a table"),
+    }
+}
+
+/// The first key of `pinned` whose value in `settings` differs from `current`.
+fn pinned_change(
+    pinned: &toml::Table,
+    current: Option<&toml::Table>,
+    settings: Option<&toml::Table>,
+    prefix: &str,
+) -> Option<String> {
+    pinned.iter().find_map(|(name, value)| {
+        let key = format!("{}{}", prefix, name);
+        let current = current.and_then(|table| table.get(name));
+        let changed = settings.and_then(|table| table.get(name));
+        match value {
+            toml::Value::Table(pinned) => pinned_change(
+                pinned,
+                current.and_then(toml::Value::as_table),
+                changed.and_then(toml::Value::as_table),
+                &format!("{}.", key),
+            ),
+            _ => (current != changed).then_some(key),
+        }
+    })
+}
 
-    Ok(SETTINGS.get_or_init(|| settings))
+/// The keys of `settings` which differ from `base`, under `prefix`. A key of
+/// `base` missing from `settings` can not be saved, toml has no null.
+fn changed_keys(base: &toml::Table, settings: &toml::Table, prefix: &str) -> Result<toml::Table> {
+    if let Some(key) = base.keys().find(|key| !settings.contains_key(*key)) {
+        bail!(
+            "{}{} is set in the config files, and can not be unset from the app",
+            prefix,
+            key
+        );
+    }
+    let mut changed = toml::Table::new();
+    for (key, value) in settings {
+        match (base.get(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(table)) => {
+                let table = changed_keys(base, table, &format!("{}{}.", prefix, key))?;
+                if !table.is_empty() {
+                    changed.insert(key.clone(), toml::Value::Table(table));
+                }
+            }
+            (Some(base), value) if base == value => {}
+            _ => {
+                changed.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(changed)
+}
+
+fn apply(settings: Settings) {
+    SETTINGS.get().unwrap().send_replace(Arc::new(settings));
+    info!("settings applied");
 }
 
-static SETTINGS: OnceLock<Settings> = OnceLock::new();
+/// Reloads the settings whenever one of the config files changes.
+///
+/// Invalid settings are not applied, the previous ones stay in use until the
+/// files are fixed. `credentials` is only read at startup.
+pub fn watch_files() {
+    let sources = sources();
+    let mut last = sources.modified();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        let current = sources.modified();
+        if current == last {
+            continue;
+        }
+        last = current;
+        match sources.read() {
+            Ok(settings) => apply(settings),
+            Err(err) => warn!(?err, "config files changed, but the settings are invalid"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_user_config_round_trip() -> Result<()> {
+        let content = BUNDLED_DEFAULTS.to_string()
+            + r#"
+            [profiles.lab]
+            http = "https://lab.example.com"
+            tcp = "lab.example.com:48371"
+            [profiles.lab.tls]
+            server_name = "lab"
+        "#;
+        let parse = |content: &str| -> Result<Settings> {
+            let settings: Settings = Config::builder()
+                .add_source(config::File::from_str(content, config::FileFormat::Toml))
+                .build()?
+                .try_deserialize()?;
+            settings.validate()?;
+            Ok(settings)
+        };
+        let settings = parse(&content)?;
 
-pub fn get_settings() -> &'static Settings {
-    SETTINGS.get().unwrap()
+        // settings saved from the app are read back the same
+        let saved = toml::to_string_pretty(&settings)?;
+        let read = parse(&saved)?;
+        assert_eq!(toml::to_string_pretty(&read)?, saved);
+        let lab = &read.profiles["lab"].server;
+        assert_eq!(
+            lab.tls.as_ref().unwrap().server_name.as_deref(),
+            Some("lab")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t_changed_keys() -> Result<()> {
+        // the bundled defaults, with a config file setting max_rate
+        let read = || -> Result<toml::Table> {
+            let settings: Settings = Config::builder()
+                .add_source(config::File::from_str(
+                    BUNDLED_DEFAULTS,
+                    config::FileFormat::Toml,
+                ))
+                .add_source(config::File::from_str(
+                    "[upload]\nmax_rate = 1000\n",
+                    config::FileFormat::Toml,
+                ))
+                .build()?
+                .try_deserialize()?;
+            to_table(&settings)
+        };
+        let base = read()?;
+        let mut settings = read()?;
+        assert!(changed_keys(&base, &settings, "")?.is_empty());
+
+        // only what differs from the other files is saved
+        let upload = settings["upload"].as_table_mut().unwrap();
+        upload.insert("parallel_streams".into(), 4.into());
+        let changed = changed_keys(&base, &settings, "")?;
+        assert_eq!(
+            toml::to_string(&changed)?,
+            "[upload]\nparallel_streams = 4\n"
+        );
+
+        let upload = settings["upload"].as_table_mut().unwrap();
+        upload.remove("max_rate");
+        let err = changed_keys(&base, &settings, "").expect_err("unset key");
+        assert!(err.to_string().starts_with("upload.max_rate "));
+        Ok(())
+    }
+
+    #[test]
+    fn t_config_arg() {
+        let args = |args: &[&str]| config_arg(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(args(&["--config", "a.toml"]), Some(PathBuf::from("a.toml")));
+        assert_eq!(
+            args(&["-v", "--config=b.toml"]),
+            Some(PathBuf::from("b.toml"))
+        );
+        assert_eq!(args(&["--config"]), None);
+        assert_eq!(args(&[]), None);
+    }
+
+    #[test]
+    fn t_config_sources() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("zcode-bench-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("config.toml"), "[upload]\nparallel_streams = 4\n")?;
+
+        // the bundled defaults are enough, the user config overrides them
+        let settings = ConfigSources::new(Some(dir.clone()), None).read()?;
+        assert_eq!(settings.upload.parallel_streams, 4);
+        assert_eq!(settings.transfer.max_concurrency, 3);
+
+        // keys of the file given with --config are not changed from the app
+        let explicit = dir.join("explicit.toml");
+        std::fs::write(&explicit, "[upload]\nchunk_size = 4096\n")?;
+        let sources = ConfigSources::new(Some(dir.clone()), Some(explicit));
+        let pinned = sources.pinned()?;
+        let current = to_table(&sources.read()?)?;
+        let mut changed = current.clone();
+        let upload = changed["upload"].as_table_mut().unwrap();
+        upload.insert("parallel_streams".into(), 2.into());
+        assert_eq!(
+            pinned_change(&pinned, Some(&current), Some(&changed), ""),
+            None
+        );
+        let upload = changed["upload"].as_table_mut().unwrap();
+        upload.insert("chunk_size".into(), 8192.into());
+        assert_eq!(
+            pinned_change(&pinned, Some(&current), Some(&changed), ""),
+            Some("upload.chunk_size".to_string())
+        );
+
+        // the error tells which files were looked for
+        let missing = dir.join("missing.toml");
+        let err = ConfigSources::new(Some(dir.clone()), Some(missing.clone()))
+            .read()
+            .err()
+            .expect("the file given with --config is required");
+        let msg = format!("{:#}", err);
+        assert!(msg.contains(&format!("{} (found)", dir.join("config.toml").display())));
+        assert!(msg.contains(&format!("{} (not found)", dir.join("user.toml").display())));
+        assert!(msg.contains(&format!("{} (not found)", missing.display())));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,19 @@
+use tracing::instrument;
+
+use crate::my_err::MyResult;
+
+use super::Settings;
+
+#[tauri::command]
+pub fn get_settings() -> Settings {
+    (*super::get_settings()).clone()
+}
+
+/// Replaces the settings, if they are valid. They are kept in the user config,
+/// and applied to the running transfers.
+#[instrument(skip_all)]
+#[tauri::command]
+pub fn update_settings(settings: Settings) -> MyResult<()> {
+    super::update_settings(settings)?;
+    Ok(())
+}
@@ -0,0 +1,1072 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU32, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures::{future::BoxFuture, FutureExt};
+use protocol::upload::ConflictPolicy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    log_if_err,
+    my_err::{MyErr, MyResult},
+    profile::DEFAULT_PROFILE,
+    rate_limit::{RateLimiter, RateLimits},
+    utils::write_atomic,
+};
+
+pub type TransferId = u32;
+
+/// how long a stopped transfer may take to wind down before it is dropped
+const STOP_GRACE: Duration = Duration::from_secs(5);
+/// events held back for a transfer nobody subscribed to, the oldest are dropped
+const MAX_PENDING_EVENTS: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    Upload,
+    UploadDir,
+    Download,
+}
+
+impl TransferKind {
+    fn event_key(&self, id: TransferId) -> String {
+        match self {
+            TransferKind::Upload => format!("upload-progress-{}", id),
+            TransferKind::UploadDir => format!("upload-dir-progress-{}", id),
+            TransferKind::Download => format!("download-progress-{}", id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferStatus {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransferInfo {
+    pub id: TransferId,
+    pub kind: TransferKind,
+    /// progress of the transfer is emitted to the window under this key
+    pub event_key: String,
+    pub src: String,
+    pub dst: String,
+    pub status: TransferStatus,
+    /// bytes the other side confirmed to hold
+    #[serde(default)]
+    pub confirmed_bytes: u64,
+    /// bytes per second, unlimited if none
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// what an upload does when its destination exists
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// profile of the server the transfer talks to
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub err_msg: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// stopped for now, the transfer can be resumed
+    Pause,
+    Cancel,
+}
+
+/// Error returned by a transfer task which has been stopped by the user.
+#[derive(Debug)]
+pub struct Stopped(pub Stop);
+
+impl Display for Stopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Stop::Pause => write!(f, "transfer paused"),
+            Stop::Cancel => write!(f, "transfer cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+/// Tells a running transfer task to stop, and why.
+#[derive(Clone, Default)]
+pub struct StopSignal {
+    token: CancellationToken,
+    reason: Arc<Mutex<Option<Stop>>>,
+}
+
+impl StopSignal {
+    fn stop(&self, reason: Stop) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.token.cancel();
+    }
+
+    pub fn reason(&self) -> Option<Stop> {
+        *self.reason.lock().unwrap()
+    }
+
+    /// Returns a [`Stopped`] error once the transfer should stop.
+    pub fn check(&self) -> Result<()> {
+        match self.reason() {
+            Some(reason) => Err(Stopped(reason).into()),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn stopped(&self) -> Stop {
+        self.token.cancelled().await;
+        self.reason().unwrap_or(Stop::Cancel)
+    }
+}
+
+/// What a running transfer task gets from the manager.
+#[derive(Clone)]
+pub struct TransferHandle {
+    id: TransferId,
+    /// progress of the transfer is sent to the frontend through it
+    pub events: TransferEvents,
+    /// must be checked regularly by the task
+    pub stop: StopSignal,
+    /// uploads wait for them before sending a chunk
+    pub limits: RateLimits,
+    manager: TransferManager,
+}
+
+impl TransferHandle {
+    pub fn set_confirmed(&self, bytes: u64) {
+        self.manager.update(self.id, |t| t.confirmed_bytes = bytes);
+    }
+
+    /// bytes the other side confirmed to hold when the transfer last stopped
+    pub fn confirmed(&self) -> u64 {
+        self.manager
+            .get(self.id)
+            .map_or(0, |info| info.confirmed_bytes)
+    }
+
+    /// Records where the transfer goes, once the other side chose another path.
+    pub fn set_dst(&self, dst: &str) {
+        self.manager.update(self.id, |t| t.dst = dst.to_string());
+    }
+
+    /// where the transfer goes, as last recorded
+    pub fn dst(&self) -> Option<String> {
+        self.manager.get(self.id).map(|info| info.dst)
+    }
+}
+
+/// Sends events of a transfer to the frontend.
+///
+/// Events are held back until the frontend subscribes to the transfer, so none
+/// of them is lost while it is still setting up its listener.
+#[derive(Clone)]
+pub struct TransferEvents {
+    id: TransferId,
+    key: String,
+    manager: TransferManager,
+    /// events nobody listens to are dropped
+    muted: bool,
+}
+
+impl TransferEvents {
+    pub fn emit(&self, payload: impl Serialize) {
+        if self.muted {
+            return;
+        }
+        match serde_json::to_value(payload) {
+            Ok(payload) => self.manager.emit(self.id, &self.key, payload),
+            Err(err) => error!(?err, key = self.key, "failed to serialize event"),
+        }
+    }
+
+    /// Events of a part of the transfer whose progress is reported by the
+    /// whole, which are not sent.
+    pub fn muted(&self) -> Self {
+        Self {
+            muted: true,
+            ..self.clone()
+        }
+    }
+}
+
+/// Last event of every transfer, sent under its event key once the transfer stops.
+#[derive(Serialize)]
+pub struct TransferEnd {
+    pub state: TransferStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<MyErr>,
+}
+
+/// Sends an event to the frontend under the given event key.
+pub type EmitFn = Arc<dyn Fn(&str, &Value) + Send + Sync>;
+
+/// Tells the name of the active profile.
+pub type ProfileFn = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// Creates the future doing a transfer, called again when the transfer is resumed.
+pub type TaskFn = Arc<dyn Fn(TransferHandle) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+struct Transfer {
+    info: TransferInfo,
+    /// creates the future doing the transfer, called again on resume
+    task: TaskFn,
+    stop: StopSignal,
+    limiter: RateLimiter,
+    /// whether the frontend listens to the events of the transfer
+    subscribed: bool,
+    /// events sent before the frontend subscribed
+    pending: VecDeque<(String, Value)>,
+}
+
+/// Keeps track of every transfer and limits how many of them run at the same time.
+///
+/// Unfinished transfers are saved to `state_file`, so they can be restored after
+/// the app restarts.
+#[derive(Clone)]
+pub struct TransferManager {
+    transfers: Arc<Mutex<BTreeMap<TransferId, Transfer>>>,
+    permits: Arc<Semaphore>,
+    concurrency: Arc<Mutex<Concurrency>>,
+    state_file: Option<Arc<PathBuf>>,
+    emit: EmitFn,
+    /// shared by all uploads
+    global_limiter: RateLimiter,
+    /// rate limit of a new transfer
+    default_rate_limit: Arc<Mutex<Option<u64>>>,
+    /// a transfer only talks to the server of the profile it was started with
+    active_profile: ProfileFn,
+}
+
+struct Concurrency {
+    max: usize,
+    /// permits to take out of the semaphore after `max` was lowered, they are
+    /// dropped once the transfers holding them finish
+    excess: usize,
+}
+
+impl TransferManager {
+    pub fn new(max_concurrency: usize, state_file: Option<PathBuf>, emit: EmitFn) -> Self {
+        Self {
+            transfers: Default::default(),
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            concurrency: Arc::new(Mutex::new(Concurrency {
+                max: max_concurrency,
+                excess: 0,
+            })),
+            state_file: state_file.map(Arc::new),
+            emit,
+            global_limiter: RateLimiter::default(),
+            default_rate_limit: Default::default(),
+            active_profile: Arc::new(|| DEFAULT_PROFILE.to_string()),
+        }
+    }
+
+    /// Tells which profile new transfers are started with.
+    pub fn with_active_profile(mut self, active_profile: ProfileFn) -> Self {
+        self.active_profile = active_profile;
+        self
+    }
+
+    /// Limits the bytes per second sent by all uploads, and by each of them.
+    pub fn with_rate_limits(self, global: Option<u64>, per_transfer: Option<u64>) -> Self {
+        self.global_limiter.set_rate(global);
+        *self.default_rate_limit.lock().unwrap() = per_transfer;
+        self
+    }
+
+    /// Changes how many transfers run at the same time. Running transfers are
+    /// not stopped when it is lowered, fewer are started until they finish.
+    pub fn set_max_concurrency(&self, max: usize) {
+        let mut concurrency = self.concurrency.lock().unwrap();
+        if max > concurrency.max {
+            let more = max - concurrency.max;
+            let kept = more.min(concurrency.excess);
+            concurrency.excess -= kept;
+            self.permits.add_permits(more - kept);
+        } else {
+            concurrency.excess += concurrency.max - max;
+        }
+        concurrency.max = max;
+    }
+
+    /// Changes the rate limit of new transfers, and of the unfinished ones
+    /// still at the previous default.
+    pub fn set_default_rate_limit(&self, rate: Option<u64>) {
+        let previous = std::mem::replace(&mut *self.default_rate_limit.lock().unwrap(), rate);
+        if previous == rate {
+            return;
+        }
+        let mut transfers = self.transfers.lock().unwrap();
+        for transfer in transfers.values_mut() {
+            let finished = matches!(
+                transfer.info.status,
+                TransferStatus::Done | TransferStatus::Cancelled
+            );
+            if !finished && transfer.info.rate_limit == previous {
+                transfer.limiter.set_rate(rate);
+                transfer.info.rate_limit = rate;
+            }
+        }
+        self.save(&transfers);
+    }
+
+    /// Waits for a free slot, taking out the permits in excess of `max_concurrency`.
+    async fn acquire_permit(&self) -> SemaphorePermit<'_> {
+        loop {
+            let permit = self.permits.acquire().await.unwrap();
+            let mut concurrency = self.concurrency.lock().unwrap();
+            if concurrency.excess == 0 {
+                return permit;
+            }
+            concurrency.excess -= 1;
+            permit.forget();
+        }
+    }
+
+    /// Registers a transfer and runs it in background once there is a free slot.
+    pub fn spawn(
+        &self,
+        kind: TransferKind,
+        src: String,
+        dst: String,
+        on_conflict: ConflictPolicy,
+        task: TaskFn,
+    ) -> TransferInfo {
+        let id = next_transfer_id();
+        let info = TransferInfo {
+            id,
+            kind,
+            event_key: kind.event_key(id),
+            src,
+            dst,
+            status: TransferStatus::Queued,
+            confirmed_bytes: 0,
+            rate_limit: *self.default_rate_limit.lock().unwrap(),
+            on_conflict,
+            profile: (self.active_profile)(),
+            err_msg: None,
+        };
+        let (task, handle) = {
+            let mut transfers = self.transfers.lock().unwrap();
+            let started = self.queue(Self::insert(&mut transfers, info.clone(), task));
+            self.save(&transfers);
+            started
+        };
+        debug!(?info, "transfer queued");
+
+        self.run(id, task, handle);
+        info
+    }
+
+    /// Registers a transfer saved by a previous run of the app.
+    ///
+    /// Interrupted transfers are paused until the user resumes them, unless `resume` is set.
+    pub fn restore(&self, saved: TransferInfo, task: TaskFn, resume: bool) -> TransferInfo {
+        let id = next_transfer_id();
+        let status = match saved.status {
+            TransferStatus::Queued | TransferStatus::Running => TransferStatus::Paused,
+            status => status,
+        };
+        let info = TransferInfo {
+            id,
+            event_key: saved.kind.event_key(id),
+            status,
+            ..saved
+        };
+        {
+            let mut transfers = self.transfers.lock().unwrap();
+            Self::insert(&mut transfers, info.clone(), task);
+            self.save(&transfers);
+        }
+        info!(?info, "transfer restored");
+
+        if resume && status == TransferStatus::Paused {
+            log_if_err!(self.start(id));
+        }
+        info
+    }
+
+    /// Transfers saved by a previous run of the app
+    pub fn load_saved(&self) -> Vec<TransferInfo> {
+        let Some(path) = &self.state_file else {
+            return vec![];
+        };
+        if !path.exists() {
+            return vec![];
+        }
+        match read_state(path) {
+            Ok(saved) => saved,
+            Err(err) => {
+                error!(?err, ?path, "failed to load saved transfers");
+                vec![]
+            }
+        }
+    }
+
+    fn insert(
+        transfers: &mut BTreeMap<TransferId, Transfer>,
+        info: TransferInfo,
+        task: TaskFn,
+    ) -> &mut Transfer {
+        let transfer = Transfer {
+            limiter: RateLimiter::new(info.rate_limit),
+            info,
+            task,
+            stop: StopSignal::default(),
+            subscribed: false,
+            pending: VecDeque::new(),
+        };
+        transfers.entry(transfer.info.id).or_insert(transfer)
+    }
+
+    fn handle(&self, transfer: &Transfer, stop: StopSignal) -> TransferHandle {
+        let id = transfer.info.id;
+        TransferHandle {
+            id,
+            events: self.events(id, transfer.info.event_key.clone()),
+            stop,
+            limits: RateLimits {
+                global: self.global_limiter.clone(),
+                transfer: transfer.limiter.clone(),
+            },
+            manager: self.clone(),
+        }
+    }
+
+    fn events(&self, id: TransferId, key: String) -> TransferEvents {
+        TransferEvents {
+            id,
+            key,
+            manager: self.clone(),
+            muted: false,
+        }
+    }
+
+    /// Sends the events held back for the transfer, and the later ones right away.
+    pub fn subscribe(&self, id: TransferId) -> Result<()> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get_mut(&id) else {
+            bail!("transfer not found: {}", id);
+        };
+        transfer.subscribed = true;
+        debug!(id, pending = transfer.pending.len(), "transfer subscribed");
+        for (key, payload) in transfer.pending.drain(..) {
+            (self.emit)(&key, &payload);
+        }
+        Ok(())
+    }
+
+    fn emit(&self, id: TransferId, key: &str, payload: Value) {
+        // events are sent with the lock held, so that they keep their order
+        let mut transfers = self.transfers.lock().unwrap();
+        match transfers.get_mut(&id) {
+            Some(transfer) if !transfer.subscribed => {
+                if transfer.pending.len() == MAX_PENDING_EVENTS {
+                    transfer.pending.pop_front();
+                }
+                transfer.pending.push_back((key.to_string(), payload));
+            }
+            _ => (self.emit)(key, &payload),
+        }
+    }
+
+    /// Runs a stopped transfer again. Its status is checked and changed under
+    /// the same lock, so it is never started twice.
+    fn start(&self, id: TransferId) -> Result<()> {
+        let (task, handle) = {
+            let mut transfers = self.transfers.lock().unwrap();
+            let Some(transfer) = transfers.get_mut(&id) else {
+                bail!("transfer not found: {}", id);
+            };
+            match transfer.info.status {
+                TransferStatus::Paused | TransferStatus::Failed => {}
+                status => bail!("cannot resume a transfer in status {:?}", status),
+            }
+            let started = self.queue(transfer);
+            self.save(&transfers);
+            started
+        };
+        self.run(id, task, handle);
+        Ok(())
+    }
+
+    /// Queues the transfer with a new stop signal, and gives what runs it.
+    fn queue(&self, transfer: &mut Transfer) -> (TaskFn, TransferHandle) {
+        transfer.stop = StopSignal::default();
+        transfer.info.status = TransferStatus::Queued;
+        transfer.info.err_msg = None;
+        (
+            transfer.task.clone(),
+            self.handle(transfer, transfer.stop.clone()),
+        )
+    }
+
+    fn run(&self, id: TransferId, task: TaskFn, handle: TransferHandle) {
+        let this = self.clone();
+        // restored transfers are started from the setup hook, outside of the tokio runtime
+        tauri::async_runtime::spawn(async move {
+            let stop = handle.stop.clone();
+            // the task is created while queued, but does nothing until it is polled
+            let task = task(handle);
+            let _permit = tokio::select! {
+                permit = this.acquire_permit() => permit,
+                reason = stop.stopped() => {
+                    this.finish(id, Err(Stopped(reason).into()));
+                    return;
+                }
+            };
+            this.update(id, |t| t.status = TransferStatus::Running);
+            info!(id, "transfer started");
+
+            let res = tokio::select! {
+                // a panic fails the transfer, rather than leaving it running for good
+                res = AssertUnwindSafe(task).catch_unwind() => res.unwrap_or_else(|panic| {
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .copied()
+                        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("unknown panic");
+                    Err(anyhow!("transfer task panicked: {}", msg))
+                }),
+                // the task is expected to stop by itself, drop it if it takes too long
+                reason = async {
+                    let reason = stop.stopped().await;
+                    tokio::time::sleep(STOP_GRACE).await;
+                    reason
+                } => Err(Stopped(reason).into()),
+            };
+            this.finish(id, res);
+        });
+    }
+
+    fn finish(&self, id: TransferId, res: Result<()>) {
+        let (state, error) = match res {
+            Ok(()) => {
+                info!(id, "transfer done");
+                (TransferStatus::Done, None)
+            }
+            Err(err) => match err.downcast_ref::<Stopped>() {
+                Some(Stopped(Stop::Pause)) => {
+                    info!(id, "transfer paused");
+                    (TransferStatus::Paused, None)
+                }
+                Some(Stopped(Stop::Cancel)) => {
+                    info!(id, "transfer cancelled");
+                    (TransferStatus::Cancelled, None)
+                }
+                None => {
+                    error!(?err, id, "transfer failed");
+                    (TransferStatus::Failed, Some(err))
+                }
+            },
+        };
+        let err_msg = error.as_ref().map(|err| format!("{:#}", err));
+        self.update(id, |t| {
+            t.status = state;
+            t.err_msg = err_msg;
+        });
+        self.emit_end(id, state, error.map(MyErr::from));
+    }
+
+    /// Tells the frontend the transfer is over, so it does not wait for more events.
+    fn emit_end(&self, id: TransferId, state: TransferStatus, error: Option<MyErr>) {
+        let Some(info) = self.get(id) else {
+            return;
+        };
+        self.events(id, info.event_key)
+            .emit(TransferEnd { state, error });
+    }
+
+    fn update(&self, id: TransferId, f: impl FnOnce(&mut TransferInfo)) {
+        let mut transfers = self.transfers.lock().unwrap();
+        if let Some(transfer) = transfers.get_mut(&id) {
+            f(&mut transfer.info);
+            self.save(&transfers);
+        }
+    }
+
+    /// Writes unfinished transfers to the state file
+    fn save(&self, transfers: &BTreeMap<TransferId, Transfer>) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let unfinished: Vec<_> = transfers
+            .values()
+            .map(|t| &t.info)
+            .filter(|t| !matches!(t.status, TransferStatus::Done | TransferStatus::Cancelled))
+            .collect();
+        log_if_err!(write_state(path, &unfinished));
+    }
+
+    /// Whether a transfer is queued or running. The stopped ones are only
+    /// resumed or discarded under the profile they were started with.
+    pub fn has_running(&self) -> bool {
+        let transfers = self.transfers.lock().unwrap();
+        transfers.values().any(|t| {
+            matches!(
+                t.info.status,
+                TransferStatus::Queued | TransferStatus::Running
+            )
+        })
+    }
+
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let transfers = self.transfers.lock().unwrap();
+        transfers.values().map(|t| t.info.clone()).collect()
+    }
+
+    pub fn get(&self, id: TransferId) -> Option<TransferInfo> {
+        let transfers = self.transfers.lock().unwrap();
+        transfers.get(&id).map(|t| t.info.clone())
+    }
+
+    pub fn pause(&self, id: TransferId) -> Result<()> {
+        let transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get(&id) else {
+            bail!("transfer not found: {}", id);
+        };
+        match transfer.info.status {
+            TransferStatus::Queued | TransferStatus::Running => {
+                transfer.stop.stop(Stop::Pause);
+                Ok(())
+            }
+            status => bail!("cannot pause a transfer in status {:?}", status),
+        }
+    }
+
+    pub fn resume(&self, id: TransferId) -> Result<()> {
+        // the profile of a transfer never changes
+        if let Some(info) = self.get(id) {
+            let active = (self.active_profile)();
+            ensure!(
+                info.profile == active,
+                "transfer was started with profile {}, switch to it to resume the transfer",
+                info.profile
+            );
+        }
+        self.start(id)
+    }
+
+    /// Changes the rate limit of a transfer, or of all uploads together if `id` is none.
+    pub fn set_rate_limit(&self, id: Option<TransferId>, rate: Option<u64>) -> Result<()> {
+        if rate == Some(0) {
+            bail!("rate limit must be greater than 0");
+        }
+        let Some(id) = id else {
+            self.global_limiter.set_rate(rate);
+            return Ok(());
+        };
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get_mut(&id) else {
+            bail!("transfer not found: {}", id);
+        };
+        transfer.limiter.set_rate(rate);
+        transfer.info.rate_limit = rate;
+        self.save(&transfers);
+        Ok(())
+    }
+
+    /// Rate limit of all uploads together
+    pub fn global_rate_limit(&self) -> Option<u64> {
+        self.global_limiter.rate()
+    }
+
+    pub fn cancel(&self, id: TransferId) -> Result<()> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get_mut(&id) else {
+            bail!("transfer not found: {}", id);
+        };
+        match transfer.info.status {
+            TransferStatus::Queued | TransferStatus::Running => {
+                transfer.stop.stop(Stop::Cancel);
+            }
+            TransferStatus::Paused | TransferStatus::Failed
+                if transfer.info.profile != (self.active_profile)() =>
+            {
+                // the server of another profile is not told to discard the partial file
+                warn!(
+                    id,
+                    profile = transfer.info.profile,
+                    "partial file left on the server"
+                );
+                transfer.info.status = TransferStatus::Cancelled;
+                let events = self.events(id, transfer.info.event_key.clone());
+                self.save(&transfers);
+                drop(transfers);
+                events.emit(TransferEnd {
+                    state: TransferStatus::Cancelled,
+                    error: None,
+                });
+            }
+            TransferStatus::Paused | TransferStatus::Failed => {
+                // the task is not running, run it once more with a stopped signal,
+                // so it can tell the server to discard the partial file
+                transfer.info.status = TransferStatus::Cancelled;
+                let stop = StopSignal::default();
+                stop.stop(Stop::Cancel);
+                let handle = self.handle(transfer, stop);
+                let discard = (transfer.task)(handle.clone());
+                self.save(&transfers);
+                drop(transfers);
+                handle.events.emit(TransferEnd {
+                    state: TransferStatus::Cancelled,
+                    error: None,
+                });
+                tauri::async_runtime::spawn(async move {
+                    match discard.await {
+                        Err(err) if !err.is::<Stopped>() => {
+                            error!(?err, id, "failed to discard partial file")
+                        }
+                        _ => {}
+                    }
+                });
+            }
+            status => bail!("cannot cancel a transfer in status {:?}", status),
+        }
+        Ok(())
+    }
+}
+
+fn read_state(path: &Path) -> Result<Vec<TransferInfo>> {
+    let content = std::fs::read(path).context("read state file")?;
+    let saved = serde_json::from_slice(&content).context("parse state file")?;
+    Ok(saved)
+}
+
+fn write_state(path: &Path, transfers: &[&TransferInfo]) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(transfers)?)
+}
+
+fn default_profile() -> String {
+    DEFAULT_PROFILE.to_string()
+}
+
+fn next_transfer_id() -> TransferId {
+    static ID: AtomicU32 = AtomicU32::new(0);
+    ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+}
+
+#[tauri::command]
+pub fn list_transfers(transfers: State<'_, TransferManager>) -> Vec<TransferInfo> {
+    transfers.list()
+}
+
+#[tauri::command]
+pub fn get_transfer(
+    transfers: State<'_, TransferManager>,
+    id: TransferId,
+) -> MyResult<TransferInfo> {
+    let info = transfers
+        .get(id)
+        .ok_or_else(|| anyhow::anyhow!("transfer not found: {}", id))?;
+    Ok(info)
+}
+
+/// Limits the bytes per second of a transfer, or of all uploads if `id` is none.
+///
+/// `bytes_per_sec` of none removes the limit.
+#[tauri::command]
+pub fn set_rate_limit(
+    transfers: State<'_, TransferManager>,
+    id: Option<TransferId>,
+    bytes_per_sec: Option<u64>,
+) -> MyResult<()> {
+    debug!(?id, ?bytes_per_sec, "setting rate limit");
+    transfers.set_rate_limit(id, bytes_per_sec)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_global_rate_limit(transfers: State<'_, TransferManager>) -> Option<u64> {
+    transfers.global_rate_limit()
+}
+
+/// Called by the frontend once it listens to the events of the transfer.
+#[tauri::command]
+pub fn subscribe_transfer(transfers: State<'_, TransferManager>, id: TransferId) -> MyResult<()> {
+    transfers.subscribe(id)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn pause_transfer(transfers: State<'_, TransferManager>, id: TransferId) -> MyResult<()> {
+    debug!(id, "pausing");
+    transfers.pause(id)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn resume_transfer(
+    transfers: State<'_, TransferManager>,
+    id: TransferId,
+) -> MyResult<()> {
+    debug!(id, "resuming");
+    transfers.resume(id)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn cancel_transfer(
+    transfers: State<'_, TransferManager>,
+    id: TransferId,
+) -> MyResult<()> {
+    debug!(id, "cancelling");
+    transfers.cancel(id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    async fn wait_status(transfers: &TransferManager, id: TransferId, status: TransferStatus) {
+        for _ in 0..100 {
+            if transfers.get(id).unwrap().status == status {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("transfer never reached {:?}", status);
+    }
+
+    #[tokio::test]
+    async fn t_pause_resume_cancel() -> Result<()> {
+        let state_file =
+            std::env::temp_dir().join(format!("transfers-{}.json", std::process::id()));
+        let ends = Arc::new(Mutex::new(vec![]));
+        let emitted = ends.clone();
+        let emit: EmitFn = Arc::new(move |_: &str, event: &Value| {
+            emitted
+                .lock()
+                .unwrap()
+                .push(event["state"].as_str().unwrap().to_string());
+        });
+        let transfers = TransferManager::new(1, Some(state_file.clone()), emit.clone());
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let counter = runs.clone();
+        let task: TaskFn = Arc::new(move |handle: TransferHandle| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                handle.set_confirmed(42);
+                let reason = handle.stop.stopped().await;
+                Err(Stopped(reason).into())
+            }
+            .boxed()
+        });
+        let info = transfers.spawn(
+            TransferKind::Upload,
+            "a".to_string(),
+            "b".to_string(),
+            ConflictPolicy::default(),
+            task.clone(),
+        );
+        assert_eq!(info.status, TransferStatus::Queued);
+
+        wait_status(&transfers, info.id, TransferStatus::Running).await;
+        transfers.pause(info.id)?;
+        wait_status(&transfers, info.id, TransferStatus::Paused).await;
+        assert!(transfers.resume(999).is_err());
+
+        // events are held back until the frontend subscribes
+        assert!(ends.lock().unwrap().is_empty());
+        transfers.subscribe(info.id)?;
+        assert_eq!(*ends.lock().unwrap(), ["paused"]);
+
+        // a restarted app gets the paused transfer back
+        let restarted = TransferManager::new(1, Some(state_file.clone()), emit.clone());
+        let saved = restarted.load_saved();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].confirmed_bytes, 42);
+        let restored = restarted.restore(saved[0].clone(), task.clone(), false);
+        assert_eq!(restored.status, TransferStatus::Paused);
+        assert_eq!(restored.src, "a");
+        assert_eq!(restored.profile, DEFAULT_PROFILE);
+
+        // under another profile, it is not resumed and the server is not told to discard it
+        let other = TransferManager::new(1, None, emit.clone())
+            .with_active_profile(Arc::new(|| "lab".to_string()));
+        let restored = other.restore(saved[0].clone(), task, false);
+        assert!(other.resume(restored.id).is_err());
+        other.cancel(restored.id)?;
+        assert_eq!(
+            other.get(restored.id).unwrap().status,
+            TransferStatus::Cancelled
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // resumed from several threads at once, it is started once
+        let runtime = tokio::runtime::Handle::current();
+        let resumed = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let _runtime = runtime.enter();
+                        transfers.resume(info.id).is_ok()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|resumed| *resumed)
+                .count()
+        });
+        assert_eq!(resumed, 1);
+        wait_status(&transfers, info.id, TransferStatus::Running).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        transfers.cancel(info.id)?;
+        wait_status(&transfers, info.id, TransferStatus::Cancelled).await;
+        assert!(transfers.resume(info.id).is_err());
+        assert_eq!(transfers.list().len(), 1);
+        assert_eq!(*ends.lock().unwrap(), ["paused", "cancelled"]);
+        assert!(
+            TransferManager::new(1, Some(state_file.clone()), emit.clone())
+                .load_saved()
+                .is_empty()
+        );
+
+        // a dir is discarded by running its task once more, stopped by the cancel,
+        // knowing how far it got to find the interrupted file
+        let runs = Arc::new(Mutex::new(vec![]));
+        let seen = runs.clone();
+        let dir_task: TaskFn = Arc::new(move |handle: TransferHandle| {
+            let seen = seen.clone();
+            async move {
+                seen.lock()
+                    .unwrap()
+                    .push((handle.stop.reason(), handle.confirmed()));
+                handle.set_confirmed(1000);
+                let reason = handle.stop.stopped().await;
+                Err(Stopped(reason).into())
+            }
+            .boxed()
+        });
+        let dir = transfers.spawn(
+            TransferKind::UploadDir,
+            "dir".to_string(),
+            "remote/dir".to_string(),
+            ConflictPolicy::default(),
+            dir_task,
+        );
+        wait_status(&transfers, dir.id, TransferStatus::Running).await;
+        transfers.pause(dir.id)?;
+        wait_status(&transfers, dir.id, TransferStatus::Paused).await;
+        transfers.cancel(dir.id)?;
+        wait_status(&transfers, dir.id, TransferStatus::Cancelled).await;
+        for _ in 0..100 {
+            if runs.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            *runs.lock().unwrap(),
+            [(None, 0), (Some(Stop::Cancel), 1000)]
+        );
+
+        std::fs::remove_file(state_file)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_task_panics() -> Result<()> {
+        let ends = Arc::new(Mutex::new(vec![]));
+        let emitted = ends.clone();
+        let emit: EmitFn = Arc::new(move |_: &str, event: &Value| {
+            emitted.lock().unwrap().push(event["state"].clone());
+        });
+        let transfers = TransferManager::new(1, None, emit);
+        let task: TaskFn = Arc::new(|_: TransferHandle| async { panic!("boom") }.boxed());
+        let info = transfers.spawn(
+            TransferKind::Upload,
+            "a".to_string(),
+            "b".to_string(),
+            ConflictPolicy::default(),
+            task,
+        );
+        transfers.subscribe(info.id)?;
+
+        // the transfer fails and the frontend is told, instead of it running for good
+        wait_status(&transfers, info.id, TransferStatus::Failed).await;
+        let info = transfers.get(info.id).unwrap();
+        assert!(info.err_msg.unwrap().contains("boom"));
+        assert!(!transfers.has_running());
+        assert_eq!(*ends.lock().unwrap(), ["failed"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_change_limits() -> Result<()> {
+        let emit: EmitFn = Arc::new(|_: &str, _: &Value| {});
+        let transfers = TransferManager::new(2, None, emit).with_rate_limits(None, Some(100));
+        let task: TaskFn = Arc::new(|handle: TransferHandle| {
+            async move {
+                let reason = handle.stop.stopped().await;
+                Err(Stopped(reason).into())
+            }
+            .boxed()
+        });
+        let ids: Vec<_> = (0..3)
+            .map(|_| {
+                let info = transfers.spawn(
+                    TransferKind::Upload,
+                    "a".to_string(),
+                    "b".to_string(),
+                    ConflictPolicy::default(),
+                    task.clone(),
+                );
+                info.id
+            })
+            .collect();
+        wait_status(&transfers, ids[1], TransferStatus::Running).await;
+        transfers.set_rate_limit(Some(ids[1]), Some(50))?;
+
+        // transfers at the previous default follow it, the others keep their own limit
+        transfers.set_default_rate_limit(Some(200));
+        let limits: Vec<_> = ids
+            .iter()
+            .map(|id| transfers.get(*id).unwrap().rate_limit)
+            .collect();
+        assert_eq!(limits, [Some(200), Some(50), Some(200)]);
+
+        // the slot of a finished transfer is taken out while over the new max
+        transfers.set_max_concurrency(1);
+        transfers.cancel(ids[0])?;
+        wait_status(&transfers, ids[0], TransferStatus::Cancelled).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            transfers.get(ids[2]).unwrap().status,
+            TransferStatus::Queued
+        );
+        transfers.cancel(ids[1])?;
+        wait_status(&transfers, ids[2], TransferStatus::Running).await;
+
+        transfers.cancel(ids[2])?;
+        wait_status(&transfers, ids[2], TransferStatus::Cancelled).await;
+        Ok(())
+    }
+}
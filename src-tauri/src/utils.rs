@@ -1,3 +1,7 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+
 pub mod http;
 
 #[macro_export]
@@ -12,3 +16,37 @@ macro_rules! log_if_err {
         }
     };
 }
+
+/// Writes `bytes` to `path` through a temp file renamed over it, so neither a
+/// crash nor a reader ever sees a truncated file.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    write_through_temp(path, bytes, false)
+}
+
+/// Like [`write_atomic`], for a file only its owner may read.
+pub fn write_atomic_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    write_through_temp(path, bytes, true)
+}
+
+fn write_through_temp(path: &Path, bytes: &[u8], private: bool) -> Result<()> {
+    let write = || -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        options.open(&tmp)?.write_all(bytes)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    write().with_context(|| format!("write {}", path.display()))
+}
@@ -75,7 +75,7 @@ macro_rules! request {
     ///////////// response ////
     (@do_request, $req:expr $(,$body_type:ident)?) => {{
         use ::anyhow::Context;
-        let resp = match $req.send().await {
+        let resp = match $crate::auth::send($req).await {
             Ok(resp) => resp,
             Err(err) => {
                 tracing::error!(?err, line = line!(), "[HTTP] failed to send request at: {}:{}", file!(), line!());
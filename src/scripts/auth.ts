@@ -0,0 +1,16 @@
+import { invoke } from "@tauri-apps/api";
+
+import { MyErr } from "./fs.ts";
+
+export async function login(username: string, password: string) {
+  await invoke("login", { username, password });
+}
+
+export async function logout() {
+  await invoke("logout");
+}
+
+/** whether a command failed because the user has to log in */
+export function isUnauthorized(err: unknown) {
+  return (err as MyErr | undefined)?.code == "unauthorized";
+}
@@ -21,34 +21,206 @@ export class FileNode implements FileProps {
   }
 }
 
-class UploadEvent {
-  constructor(
-    public percent: string,
-    public is_done: boolean = false,
-    public path: string,
-    public toDir: string
-  ) {}
+export interface TransferInfo {
+  id: number;
+  kind: "upload" | "upload_dir" | "download";
+  event_key: string;
+  src: string;
+  dst: string;
+  status: "queued" | "running" | "paused" | "done" | "failed" | "cancelled";
+  err_msg?: string;
+  confirmed_bytes: number;
+  /** bytes per second, unlimited if null */
+  rate_limit: number | null;
+  on_conflict: ConflictPolicy;
+  /** resumed only while this profile is active */
+  profile: string;
+}
+
+export async function listTransfers() {
+  let transfers: TransferInfo[] = await invoke("list_transfers");
+  return transfers;
+}
+
+/** events sent before this call are held back, so listen first */
+export async function subscribeTransfer(id: number) {
+  await invoke("subscribe_transfer", { id });
+}
+
+export async function pauseTransfer(id: number) {
+  await invoke("pause_transfer", { id });
+}
+
+export async function resumeTransfer(id: number) {
+  await invoke("resume_transfer", { id });
+}
+
+/** limits a transfer, or all uploads if `id` is null. a null limit removes it */
+export async function setRateLimit(id: number | null, bytesPerSec: number | null) {
+  await invoke("set_rate_limit", { id, bytesPerSec });
+}
+
+export async function getGlobalRateLimit() {
+  let limit: number | null = await invoke("get_global_rate_limit");
+  return limit;
+}
+
+export async function cancelTransfer(id: number) {
+  await invoke("cancel_transfer", { id });
+}
+
+export type UploadState =
+  | "queued"
+  | "running"
+  | "verifying"
+  | "committing"
+  | "skipped"
+  | "done"
+  | "failed"
+  | "paused"
+  | "cancelled";
+
+export interface Progress {
+  sent_bytes: number;
+  total_bytes: number;
+  percent: number;
+  /** bytes per second, smoothed over the last samples */
+  rate: number;
+  /** bytes per second since the transfer started */
+  avg_rate: number;
+  eta_secs?: number;
+}
+
+class UploadEvent implements Progress {
+  public state: UploadState = "queued";
+  public sent_bytes = 0;
+  public total_bytes = 0;
+  public percent = 0;
+  public rate = 0;
+  public avg_rate = 0;
+  public eta_secs?: number;
+  public error?: MyErr;
+  constructor(public path: string, public toDir: string) {}
+}
+
+export interface MyErr {
+  msg: string;
+  /** set for errors the frontend can handle, e.g. "destination_exists" */
+  code?: string;
+  path?: string;
+}
+
+/** what an upload does when its destination exists */
+export type ConflictPolicy = "overwrite" | "skip" | "rename" | "fail";
+
+/** last event of every run of a transfer, a paused one may be resumed later */
+export interface TransferEnd {
+  state: "done" | "failed" | "paused" | "cancelled";
+  error?: MyErr;
+}
+
+/** whether no more event will be sent, unless a failed transfer is resumed */
+function isEnd(state: string) {
+  return state == "done" || state == "failed" || state == "cancelled";
 }
 
 import { ref } from "vue";
 
-export async function upload(toDir: string, path: string) {
+export async function upload(
+  toDir: string,
+  path: string,
+  onConflict: ConflictPolicy = "fail"
+) {
   console.log("uploading:", path);
 
-  const progress = ref(new UploadEvent("0", false, path, toDir));
+  const progress = ref(new UploadEvent(path, toDir));
 
-  let event_key: string = await invoke("upload_file", {
-    local_path: path,
-    to_dir: toDir,
+  let { id, event_key }: TransferInfo = await invoke("upload_file", {
+    localPath: path,
+    toDir,
+    onConflict,
   });
 
-  const unlisten = await listen<UploadEvent>(event_key, (event) => {
-    progress.value.percent = event.payload.percent;
-    progress.value.is_done = event.payload.is_done;
-    if (event.payload.is_done) {
+  const unlisten = await listen<UploadEvent | TransferEnd>(event_key, (event) => {
+    Object.assign(progress.value, event.payload);
+    if (isEnd(event.payload.state)) {
       unlisten();
     }
   });
+  await subscribeTransfer(id);
+  return progress;
+}
+
+class DirUploadEvent implements Progress {
+  public total_files = 0;
+  public done_files = 0;
+  public state: UploadState = "queued";
+  public sent_bytes = 0;
+  public total_bytes = 0;
+  public percent = 0;
+  public rate = 0;
+  public avg_rate = 0;
+  public eta_secs?: number;
+  public error?: MyErr;
+}
+
+export async function uploadDir(
+  toDir: string,
+  path: string,
+  onConflict: ConflictPolicy = "fail"
+) {
+  console.log("uploading dir:", path);
+
+  const progress = ref(new DirUploadEvent());
+
+  let { id, event_key }: TransferInfo = await invoke("upload_dir", {
+    localPath: path,
+    toDir,
+    onConflict,
+  });
+
+  const unlisten = await listen<DirUploadEvent | TransferEnd>(event_key, (event) => {
+    Object.assign(progress.value, event.payload);
+    if (isEnd(event.payload.state)) {
+      unlisten();
+    }
+  });
+  await subscribeTransfer(id);
+  return progress;
+}
+
+class DownloadEvent implements Progress {
+  public state: string = "running";
+  public sent_bytes = 0;
+  public total_bytes = 0;
+  public percent = 0;
+  public rate = 0;
+  public avg_rate = 0;
+  public eta_secs?: number;
+  public error?: MyErr;
+  constructor(public path: string, public toDir: string) {}
+}
+
+export async function download(remotePath: string, toDir: string) {
+  console.log("downloading:", remotePath);
+
+  const progress = ref(new DownloadEvent(remotePath, toDir));
+
+  let { id, event_key }: TransferInfo = await invoke("download_file", {
+    remotePath,
+    toDir,
+  });
+
+  const unlisten = await listen<DownloadEvent | TransferEnd>(
+    event_key,
+    (event) => {
+      Object.assign(progress.value, event.payload);
+      if ("state" in event.payload && isEnd(event.payload.state)) {
+        unlisten();
+      }
+    }
+  );
+  await subscribeTransfer(id);
   return progress;
 }
 
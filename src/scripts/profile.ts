@@ -0,0 +1,34 @@
+import { invoke } from "@tauri-apps/api";
+
+export interface ProfileInfo {
+  name: string;
+  display_name: string;
+  http: string;
+  tcp: string;
+  active: boolean;
+  /** profiles of the settings can not be replaced from the app */
+  configured: boolean;
+}
+
+export interface NewProfile {
+  name: string;
+  display_name?: string;
+  /** key of the session in the credential store, the name of the profile if not set */
+  credentials?: string;
+  http: string;
+  tcp: string;
+}
+
+export async function listProfiles() {
+  let profiles: ProfileInfo[] = await invoke("list_profiles");
+  return profiles;
+}
+
+export async function addProfile(profile: NewProfile) {
+  await invoke("add_profile", { profile });
+}
+
+/** everything loaded from the previous server has to be loaded again */
+export async function switchProfile(name: string) {
+  await invoke("switch_profile", { name });
+}
@@ -0,0 +1,39 @@
+import { invoke } from "@tauri-apps/api";
+import { listen } from "@tauri-apps/api/event";
+
+export interface UploadSettings {
+  chunk_size: number;
+  /** bytes per second, unlimited if null */
+  max_rate: number | null;
+  max_rate_per_transfer: number | null;
+  parallel_streams: number;
+  parallel_min_size: number;
+  compression: "none" | "zstd" | "lz4";
+}
+
+export interface TransferSettings {
+  max_concurrency: number;
+  resume_on_startup: boolean;
+}
+
+/** sections not edited in the app are sent back as they were received */
+export interface Settings {
+  upload: UploadSettings;
+  transfer: TransferSettings;
+  [section: string]: unknown;
+}
+
+export async function getSettings() {
+  let settings: Settings = await invoke("get_settings");
+  return settings;
+}
+
+/** fails without changing anything if the settings are invalid */
+export async function updateSettings(settings: Settings) {
+  await invoke("update_settings", { settings });
+}
+
+/** also called when the config files are edited outside of the app */
+export async function onSettingsChanged(f: (settings: Settings) => void) {
+  return await listen<Settings>("settings-changed", (event) => f(event.payload));
+}